@@ -1,4 +1,31 @@
-use std::{thread, time::Instant};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Instant,
+};
+
+// Set once stdout carries sequence data. All status messages must then be written to stderr instead.
+static STDOUT_IS_DATA: AtomicBool = AtomicBool::new(false);
+
+pub fn reserve_stdout_for_data() {
+    STDOUT_IS_DATA.store(true, Ordering::Relaxed);
+}
+
+pub fn stdout_is_data() -> bool {
+    STDOUT_IS_DATA.load(Ordering::Relaxed)
+}
+
+// Drop-in replacement for println!() that respects whether stdout is reserved for sequence data.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::auxiliary::stdout_is_data() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+pub(crate) use status;
 
 pub fn timedrun<F, R>(msg: &str, func: F) -> R
 where
@@ -6,7 +33,7 @@ where
 {
     let start = Instant::now();
     let measure = func();
-    println!("{msg} after {:.1} seconds", start.elapsed().as_secs_f32());
+    status!("{msg} after {:.1} seconds", start.elapsed().as_secs_f32());
     measure
 }
 
@@ -42,6 +69,12 @@ mod tests {
         assert!(threads > 0);
     }
 
+    #[test]
+    fn test_stdout_is_data_after_reservation() {
+        reserve_stdout_for_data();
+        assert!(stdout_is_data());
+    }
+
     #[test]
     fn test_threads_per_task_never_returns_less_than_one() {
         let threads_per_task = threads_per_task(1, 3);
@@ -59,4 +92,10 @@ mod tests {
         let threads_per_task = threads_per_task(10, 3);
         assert!(threads_per_task == 3);
     }
+
+    #[test]
+    fn test_threads_per_task_assigns_all_threads_to_single_task() {
+        let threads_per_task = threads_per_task(8, 1);
+        assert!(threads_per_task == 7);
+    }
 }
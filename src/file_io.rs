@@ -1,20 +1,38 @@
+use super::auxiliary::status;
+use super::gzi::{GziIndex, GziIndexer};
+use super::ubam::{self, BamReader, InputRole, ReadGroup, SamReader};
 use super::umi_errors::RuntimeErrors;
 use anyhow::{anyhow, Context, Result};
 use bio::io::fastq::{Reader as FastqReader, Record, Writer as FastqWriter};
 use dialoguer::{theme::ColorfulTheme, Confirm};
-use file_format::FileFormat;
-use gzp::{deflate::Gzip, par::compress::Compression, ZBuilder, ZWriter};
+use gzp::{
+    deflate::{Bgzf, Gzip},
+    par::compress::Compression,
+    ZBuilder, ZWriter,
+};
 use regex::Regex;
-use std::{fs, fs::File, io::BufWriter, path::Path, path::PathBuf};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+    path::PathBuf,
+    rc::Rc,
+};
 
 ////////////////////////////////////////////////////////////////
 //  READ INPUT FILE
 ////////////////////////////////////////////////////////////////
 
-// Enum for the two acceptable input file formats: '.fastq' and '.fastq.gz'
+// Enum for the acceptable input file formats: '.fastq', '.fastq.gz' and '.fastq.zst'
 pub enum InputFile {
-    Plain(std::io::BufReader<File>),
-    Compressed(Box<flate2::bufread::MultiGzDecoder<std::io::BufReader<File>>>),
+    Plain(std::io::BufReader<Box<dyn Read>>),
+    Compressed(Box<flate2::bufread::MultiGzDecoder<std::io::BufReader<Box<dyn Read>>>>),
+    Zstd(Box<zstd::stream::read::Decoder<'static, std::io::BufReader<Box<dyn Read>>>>),
+    // Decompressed input, whose first bytes have been read to detect the record format.
+    Peeked(Box<std::io::Chain<std::io::Cursor<Vec<u8>>, InputFile>>),
 }
 
 // Implement read for InputFile enum
@@ -23,40 +41,329 @@ impl std::io::Read for InputFile {
         match self {
             InputFile::Plain(buf_reader) => buf_reader.read(into),
             InputFile::Compressed(buf_reader) => buf_reader.read(into),
+            InputFile::Zstd(decoder) => decoder.read(into),
+            InputFile::Peeked(chain) => chain.read(into),
         }
     }
 }
 
-// Read input file to Reader. Automatically scans if input is compressed with file-format crate.
+// Compression formats recognized by their magic bytes at the start of a stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputCompression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl InputCompression {
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            InputCompression::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            InputCompression::Zstd
+        } else if magic.starts_with(b"BZh") {
+            InputCompression::Bzip2
+        } else {
+            InputCompression::None
+        }
+    }
+}
+
+// Opens an input file and decompresses it. Compression is detected from the first bytes, which are put back in front
+// of the stream, so that it is opened only once. Hence, FIFOs, process substitutions like /dev/fd/N and stdin are
+// supported as well.
+fn open_input(path: &PathBuf) -> Result<InputFile> {
+    let mut source: Box<dyn Read> = if is_stdio(path) {
+        Box::new(std::io::stdin())
+    } else {
+        fs::metadata(path).map_err(|_e| anyhow!(RuntimeErrors::FileNotFound(Some(path.into()))))?;
+        Box::new(File::open(path).with_context(|| format!("Failed to open file: {:?}", path))?)
+    };
+
+    // A single read may return less than the magic bytes, e.g. from a pipe, hence read until they are complete.
+    let start = read_start(&mut source, |start| start.len() >= 4)
+        .with_context(|| format!("Failed to read from {:?}", path))?;
+    let compression = InputCompression::detect(&start);
+    let source: Box<dyn Read> = Box::new(std::io::Cursor::new(start).chain(source));
+    let buffer = std::io::BufReader::new(source);
+
+    match compression {
+        InputCompression::Gzip => Ok(InputFile::Compressed(Box::new(
+            flate2::bufread::MultiGzDecoder::new(buffer),
+        ))),
+        InputCompression::Zstd => Ok(InputFile::Zstd(Box::new(
+            zstd::stream::read::Decoder::with_buffer(buffer)
+                .with_context(|| format!("Failed to read from {:?}", path))?,
+        ))),
+        InputCompression::None => Ok(InputFile::Plain(buffer)),
+        InputCompression::Bzip2 => Err(anyhow!(RuntimeErrors::UnsupportedCompression(
+            path.into(),
+            compression
+        ))),
+    }
+}
+
+// Upper bound of the bytes read to detect the record format of an input file.
+const PEEK_LIMIT: usize = 1 << 16;
+
+// Reads from the start of a stream until `done` accepts the bytes read so far or the stream ends. The bytes have to
+// be put back in front of the stream by the caller.
+fn read_start(source: &mut impl Read, done: impl Fn(&[u8]) -> bool) -> std::io::Result<Vec<u8>> {
+    let mut start = Vec::new();
+    let mut chunk = [0u8; 4096];
+    while !done(&start) {
+        match source.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => start.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(start)
+}
+
+// Read input file to Reader.
 pub fn read_fastq(path: &PathBuf) -> Result<bio::io::fastq::Reader<std::io::BufReader<InputFile>>> {
-    fs::metadata(path).map_err(|_e| anyhow!(RuntimeErrors::FileNotFound(Some(path.into()))))?;
-
-    let format = FileFormat::from_file(path).context("Failed to determine file format")?;
-    let reader: InputFile = match format {
-        FileFormat::Gzip => {
-            let file = File::open(path)
-                .map(std::io::BufReader::new)
-                .with_context(|| format!("Failed to open file: {:?}", path))?;
-            InputFile::Compressed(Box::new(flate2::bufread::MultiGzDecoder::new(file)))
+    Ok(FastqReader::new(open_input(path)?))
+}
+
+pub type FastqRecords = bio::io::fastq::Records<std::io::BufReader<InputFile>>;
+
+// Records of a FastQ, SAM or BAM input file. SAM and BAM records are converted to FastQ records.
+pub enum InputRecords {
+    Fastq(FastqRecords),
+    Sam(SamReader<std::io::BufReader<InputFile>>, InputRole),
+    Bam(BamReader<std::io::BufReader<InputFile>>, InputRole),
+    // One of the two mates of an interleaved input.
+    Mate(Rc<RefCell<Interleaved>>, usize),
+}
+
+// An interleaved input, whose records alternate between both mates. Records read ahead for the other mate are
+// queued until it is iterated, so that both mates can be consumed independently, e.g. in lockstep.
+pub struct Interleaved {
+    records: InputRecords,
+    queues: [VecDeque<Record>; 2],
+    next_mate: usize,
+}
+
+// Splits an interleaved input into the records of the first and the second mate.
+pub fn deinterleave(records: InputRecords) -> (InputRecords, InputRecords) {
+    let interleaved = Rc::new(RefCell::new(Interleaved {
+        records,
+        queues: [VecDeque::new(), VecDeque::new()],
+        next_mate: 0,
+    }));
+    (
+        InputRecords::Mate(Rc::clone(&interleaved), 0),
+        InputRecords::Mate(interleaved, 1),
+    )
+}
+
+impl Interleaved {
+    fn next_of(&mut self, mate: usize) -> Option<Result<Record>> {
+        if let Some(record) = self.queues[mate].pop_front() {
+            return Some(Ok(record));
         }
-        _ => {
-            let file =
-                File::open(path).with_context(|| format!("Failed to open file: {:?}", path))?;
-            InputFile::Plain(std::io::BufReader::new(file))
+        loop {
+            let record = match self.records.next()? {
+                Ok(record) => record,
+                Err(err) => return Some(Err(err)),
+            };
+            let owner = self.next_mate;
+            self.next_mate = 1 - owner;
+            if owner == mate {
+                return Some(Ok(record));
+            }
+            self.queues[owner].push_back(record);
         }
-    };
+    }
+}
 
-    Ok(FastqReader::new(reader))
+impl From<FastqRecords> for InputRecords {
+    fn from(records: FastqRecords) -> Self {
+        InputRecords::Fastq(records)
+    }
+}
+
+impl Iterator for InputRecords {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (record, role) = match self {
+                InputRecords::Fastq(records) => return records.next().map(|r| Ok(r?)),
+                InputRecords::Sam(reader, role) => (reader.read_record(), *role),
+                InputRecords::Bam(reader, role) => (reader.read_record(), *role),
+                InputRecords::Mate(interleaved, mate) => {
+                    return interleaved.borrow_mut().next_of(*mate)
+                }
+            };
+            match record {
+                Ok(Some(record)) => {
+                    // Records that do not belong to this input, e.g. the other mate, are skipped.
+                    if let Some(record) = record.into_fastq(role) {
+                        return Some(Ok(record));
+                    }
+                }
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+// Read records from a FastQ, SAM or BAM input file. The format is detected from the decompressed content.
+// The role determines which records of a SAM or BAM file are used and where the UMI is taken from.
+pub fn read_records(path: &PathBuf, role: InputRole) -> Result<InputRecords> {
+    // SAM detection needs the complete first line, BAM detection only the magic bytes.
+    let mut file = open_input(path)?;
+    let start = read_start(&mut file, |start| {
+        start.starts_with(b"BAM\x01") || start.contains(&b'\n') || start.len() >= PEEK_LIMIT
+    })
+    .with_context(|| format!("Failed to read from {:?}", path))?;
+    let is_bam = start.starts_with(b"BAM\x01");
+    let is_sam = is_sam(&start);
+    let buffer = std::io::BufReader::new(InputFile::Peeked(Box::new(
+        std::io::Cursor::new(start).chain(file),
+    )));
+
+    if is_bam {
+        Ok(InputRecords::Bam(BamReader::new(buffer)?, role))
+    } else if is_sam {
+        Ok(InputRecords::Sam(SamReader::new(buffer), role))
+    } else {
+        Ok(FastqReader::from_bufread(buffer).records().into())
+    }
+}
+
+// SAM files start with a header line or, without a header, with a record of at least 11 tab-separated fields.
+fn is_sam(start: &[u8]) -> bool {
+    let header = [b"@HD\t", b"@SQ\t", b"@RG\t", b"@PG\t", b"@CO\t"];
+    let first_line = start.split(|&b| b == b'\n').next().unwrap_or_default();
+    header.iter().any(|tag| start.starts_with(*tag))
+        || first_line.iter().filter(|&&b| b == b'\t').count() >= 10
+}
+
+// Iterates over several input files in lockstep and fails, if one of them ends before the others.
+pub struct LockstepRecords {
+    inputs: Vec<(PathBuf, InputRecords)>,
+    count: usize,
+}
+
+impl LockstepRecords {
+    pub fn new(inputs: Vec<(PathBuf, InputRecords)>) -> Self {
+        LockstepRecords { inputs, count: 0 }
+    }
+}
+
+impl Iterator for LockstepRecords {
+    type Item = Result<Vec<Record>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut records = Vec::with_capacity(self.inputs.len());
+        let mut exhausted: Option<&PathBuf> = None;
+
+        for (path, input) in self.inputs.iter_mut() {
+            match input.next() {
+                Some(Ok(record)) => records.push(record),
+                Some(Err(err)) => {
+                    return Some(Err(
+                        err.context(format!("Failed to read records from {}", path.display()))
+                    ))
+                }
+                None => exhausted = exhausted.or(Some(path)),
+            }
+        }
+
+        match exhausted {
+            None => {
+                self.count += 1;
+                Some(Ok(records))
+            }
+            // All inputs ended simultaneously.
+            Some(_) if records.is_empty() => None,
+            Some(path) => Some(Err(anyhow!(RuntimeErrors::TruncatedInput(
+                path.clone(),
+                self.count
+            )))),
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////
 // WRITE OUTPUT FILE
 ////////////////////////////////////////////////////////////////
 
-// Enum for the two accepted output formats, '.fastq' and '.fastq.gz'
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum OutputFormat {
+    // One FastQ file per read.
+    Fastq,
+    // A single unaligned BAM file with the reads of each fragment as consecutive mates.
+    Bam,
+    // A single FastQ file with the reads of each fragment written alternately.
+    Interleaved,
+}
+
+// Enum for the accepted output formats, '.fastq', '.fastq.gz', '.fastq.zst' and '.bam'
 pub enum OutputFile {
-    Plain(FastqWriter<File>),
-    Compressed(FastqWriter<Box<dyn ZWriter>>),
+    Plain(FastqWriter<Box<dyn Write + Send>>),
+    // gzip or BGZF output, optionally with a .gzi index.
+    Compressed(CompressedWriter<Box<dyn ZWriter>>, Option<GziIndex>),
+    Zstd(CompressedWriter<zstd::stream::Encoder<'static, Box<dyn Write + Send>>>),
+    Bam(BamWriter),
+}
+
+// Writes FastQ records to a compressed stream. The encoder is kept, so that writing the end of the stream in
+// `OutputFile::finish` can report errors instead of losing them on drop.
+pub struct CompressedWriter<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> CompressedWriter<W> {
+    fn new(encoder: W) -> Self {
+        CompressedWriter {
+            writer: BufWriter::new(encoder),
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        self.writer.write_all(b"@")?;
+        self.writer.write_all(record.id().as_bytes())?;
+        if let Some(desc) = record.desc() {
+            self.writer.write_all(b" ")?;
+            self.writer.write_all(desc.as_bytes())?;
+        }
+        self.writer.write_all(b"\n")?;
+        self.writer.write_all(record.seq())?;
+        self.writer.write_all(b"\n+\n")?;
+        self.writer.write_all(record.qual())?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn into_inner(self) -> std::io::Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+
+    // Like `into_inner`, but returns the encoder even if flushing the buffered output failed.
+    fn into_parts(self) -> (std::io::Result<()>, W) {
+        match self.writer.into_inner() {
+            Ok(encoder) => (Ok(()), encoder),
+            Err(e) => {
+                let (error, writer) = e.into_parts();
+                (Err(error), writer.into_parts().0)
+            }
+        }
+    }
+}
+
+// Writes unaligned BAM records. The records of a fragment are written one after another and flagged as mates.
+pub struct BamWriter {
+    writer: BufWriter<Box<dyn ZWriter>>,
+    read_group: ReadGroup,
+    mates: usize,
+    written: usize,
 }
 
 impl OutputFile {
@@ -65,9 +372,117 @@ impl OutputFile {
             OutputFile::Plain(writer) => writer
                 .write(record.id(), record.desc(), record.seq(), record.qual())
                 .map_err(|_| anyhow!(RuntimeErrors::ReadWriteError(record))),
-            OutputFile::Compressed(writer) => writer
-                .write(record.id(), record.desc(), record.seq(), record.qual())
+            OutputFile::Compressed(writer, _) => writer
+                .write(&record)
+                .map_err(|_| anyhow!(RuntimeErrors::ReadWriteError(record))),
+            OutputFile::Zstd(writer) => writer
+                .write(&record)
                 .map_err(|_| anyhow!(RuntimeErrors::ReadWriteError(record))),
+            OutputFile::Bam(bam) => {
+                let flag = ubam::flag(bam.written % bam.mates, bam.mates);
+                let encoded = ubam::encode(&record, flag, &bam.read_group)?;
+                bam.written += 1;
+                bam.writer
+                    .write_all(&encoded)
+                    .map_err(|_| anyhow!(RuntimeErrors::ReadWriteError(record)))
+            }
+        }
+    }
+
+    // Flushes the output and completes the compressed stream. Must be called once all records are written,
+    // since errors while finishing the stream on drop would go unnoticed.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            OutputFile::Plain(mut writer) => writer.flush()?,
+            OutputFile::Compressed(writer, index) => {
+                // The encoder is finished even if flushing failed, since gzp panics when dropping it unfinished.
+                // If finishing fails once the writer thread is gone, dropping it would panic too, so it is leaked.
+                let (flushed, mut encoder) = writer.into_parts();
+                let finished = encoder.finish();
+                if finished.is_err() {
+                    std::mem::forget(encoder);
+                }
+                flushed?;
+                finished?;
+                // The index is complete once the EOF block has been written.
+                if let Some(index) = index {
+                    index.write()?;
+                }
+            }
+            OutputFile::Zstd(writer) => {
+                writer.into_inner()?.finish()?.flush()?;
+            }
+            OutputFile::Bam(bam) => {
+                let mut writer = bam.writer.into_inner().map_err(|e| e.into_error())?;
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Opens a file for writing, or stdout.
+fn open_output(path: &PathBuf) -> Result<Box<dyn Write + Send>> {
+    if is_stdio(path) {
+        Ok(Box::new(std::io::stdout()))
+    } else {
+        Ok(Box::new(File::create(path).map_err(|_e| {
+            anyhow!(RuntimeErrors::OutputNotWriteable(Some(path.clone())))
+        })?))
+    }
+}
+
+// Compression options of the output files, shared by all subcommands.
+#[derive(Debug, clap::Args)]
+pub struct OutputCompression {
+    #[clap(
+        short = 'z',
+        long = "gzip",
+        help = "Compress output files. Turned off by default.
+        \n "
+    )]
+    pub gzip: bool,
+    #[clap(
+        short = 'l',
+        long = "compression_level",
+        help = "Choose the compression level: Maximum 9, or 22 for zstd, defaults to 3. Higher numbers result in smaller files but take longer to compress.
+        \n "
+    )]
+    pub compression_level: Option<u32>,
+    #[clap(
+        long = "compression_format",
+        value_enum,
+        requires = "gzip",
+        help = "Format of compressed output files: `gzip`, `bgzf`, the blocked gzip of bgzip and htslib, or `zstd`. BGZF files remain readable by any gzip tool, but allow random access and parallel decompression. Zstandard decompresses considerably faster. Defaults to `gzip`.
+        \n "
+    )]
+    pub compression_format: Option<CompressionFormat>,
+    #[clap(
+        long = "gzi",
+        requires = "compression_format",
+        help = "Write a .gzi index alongside each BGZF-compressed output file, like `bgzip -i`.
+        \n "
+    )]
+    pub gzi: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum CompressionFormat {
+    // Multi-member gzip.
+    Gzip,
+    // Blocked gzip as written by bgzip, which allows random access and parallel decompression.
+    Bgzf,
+    // Zstandard, which decompresses considerably faster than gzip.
+    Zstd,
+}
+
+impl CompressionFormat {
+    // File extension of compressed output files.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CompressionFormat::Gzip | CompressionFormat::Bgzf => "gz",
+            CompressionFormat::Zstd => "zst",
         }
     }
 }
@@ -75,37 +490,214 @@ impl OutputFile {
 pub fn create_writer(
     path: PathBuf,
     compress: &bool,
+    compression_format: &Option<CompressionFormat>,
+    gzi: &bool,
     num_threads: &usize,
     compression_level: &Option<u32>,
     pin_at: Option<usize>,
 ) -> Result<OutputFile> {
-    let file = File::create(&path)
-        .map_err(|_e| anyhow!(RuntimeErrors::OutputNotWriteable(Some(path.clone()))))?;
-    if *compress {
-        let writer = ZBuilder::<Gzip, _>::new()
+    let format = compression_format.unwrap_or(CompressionFormat::Gzip);
+    // The index is written alongside the output file, hence it requires a BGZF-compressed file.
+    if *gzi && (!*compress || format != CompressionFormat::Bgzf || is_stdio(&path)) {
+        return Err(anyhow!(RuntimeErrors::GziUnavailable));
+    }
+    let mut file = open_output(&path)?;
+    let mut index = None;
+    if *gzi {
+        let mut index_path = path.clone().into_os_string();
+        index_path.push(".gzi");
+        let indexer = GziIndexer::new(file, index_path.into());
+        index = Some(indexer.index());
+        file = Box::new(indexer);
+    }
+    if !*compress {
+        return Ok(OutputFile::Plain(FastqWriter::new(file)));
+    }
+    let level =
+        compression_level.map_or_else(Default::default, |l| Compression::new((l).clamp(1, 9)));
+    let writer = match format {
+        CompressionFormat::Gzip => ZBuilder::<Gzip, _>::new()
             .num_threads(*num_threads)
-            .compression_level(
-                compression_level
-                    .map_or_else(Default::default, |l| Compression::new((l).clamp(1, 9))),
-            )
+            .compression_level(level)
             .pin_threads(pin_at)
-            .from_writer(file);
-        Ok(OutputFile::Compressed(FastqWriter::from_bufwriter(
-            BufWriter::new(writer),
-        )))
-    } else {
-        Ok(OutputFile::Plain(FastqWriter::new(file)))
+            .from_writer(file),
+        CompressionFormat::Bgzf => ZBuilder::<Bgzf, _>::new()
+            .num_threads(*num_threads)
+            .compression_level(level)
+            .pin_threads(pin_at)
+            .from_writer(file),
+        CompressionFormat::Zstd => {
+            // Zstandard supports levels up to 22 and, like gzip output, defaults to 3.
+            let level = compression_level.map_or(3, |l| l.clamp(1, 22)) as i32;
+            let mut encoder = zstd::stream::Encoder::new(file, level)
+                .map_err(|_e| anyhow!(RuntimeErrors::OutputNotWriteable(Some(path.clone()))))?;
+            if *num_threads > 1 {
+                encoder
+                    .multithread(*num_threads as u32)
+                    .context("Failed to start the zstd compression threads")?;
+            }
+            return Ok(OutputFile::Zstd(CompressedWriter::new(encoder)));
+        }
+    };
+    Ok(OutputFile::Compressed(CompressedWriter::new(writer), index))
+}
+
+// Creates an unaligned BAM writer for fragments of `mates` reads, compressed with BGZF.
+pub fn create_bam_writer(
+    path: PathBuf,
+    num_threads: &usize,
+    compression_level: &Option<u32>,
+    pin_at: Option<usize>,
+    read_group: ReadGroup,
+    mates: usize,
+) -> Result<OutputFile> {
+    if !(1..=2).contains(&mates) {
+        return Err(anyhow!(RuntimeErrors::BamMateCount(mates)));
     }
+    let file = open_output(&path)?;
+    let bgzf = ZBuilder::<Bgzf, _>::new()
+        .num_threads(*num_threads)
+        .compression_level(
+            compression_level.map_or_else(Default::default, |l| Compression::new((l).clamp(1, 9))),
+        )
+        .pin_threads(pin_at)
+        .from_writer(file);
+    let mut writer = BufWriter::new(bgzf);
+    writer
+        .write_all(&ubam::header(&read_group))
+        .map_err(|_e| anyhow!(RuntimeErrors::OutputNotWriteable(Some(path))))?;
+    Ok(OutputFile::Bam(BamWriter {
+        writer,
+        read_group,
+        mates,
+        written: 0,
+    }))
+}
+
+// Creates the single interleaved FastQ output for all read files. Like for BAM output, its path is taken from the
+// only output path specified, or derived from the first read file.
+pub fn create_interleaved_output(
+    reads: &[(PathBuf, Option<PathBuf>)],
+    compress: &bool,
+    compression_format: &Option<CompressionFormat>,
+    gzi: &bool,
+    force: &bool,
+    num_threads: &usize,
+    compression_level: &Option<u32>,
+) -> Result<OutputFile> {
+    let mut outputs = reads.iter().filter_map(|(_, output)| output.clone());
+    let output = outputs.next();
+    if outputs.next().is_some() {
+        return Err(anyhow!(RuntimeErrors::SingleOutput("Interleaved")));
+    }
+    let mut output = resolve_outputpath(output, &reads[0].0)?;
+    output = rectify_extension(output, compress, compression_format)?;
+    output = check_outputpath(output, force)?;
+    status!("Output will be saved to: {}", output.to_string_lossy());
+
+    create_writer(
+        output,
+        compress,
+        compression_format,
+        gzi,
+        num_threads,
+        compression_level,
+        None,
+    )
+}
+
+// Creates the single BAM output for all read files. Its path is taken from the only output path specified,
+// or derived from the first read file. The read group defaults to a sample named after that file.
+pub fn create_bam_output(
+    reads: &[(PathBuf, Option<PathBuf>)],
+    gzi: &bool,
+    force: &bool,
+    num_threads: &usize,
+    compression_level: &Option<u32>,
+    read_group: Option<ReadGroup>,
+) -> Result<OutputFile> {
+    // BAM files are indexed with .csi or .bai files of aligned reads instead.
+    if *gzi {
+        return Err(anyhow!(RuntimeErrors::GziUnavailable));
+    }
+    let mut outputs = reads.iter().filter_map(|(_, output)| output.clone());
+    let output = outputs.next();
+    if outputs.next().is_some() {
+        return Err(anyhow!(RuntimeErrors::SingleOutput("BAM")));
+    }
+    let input = &reads[0].0;
+    let mut output = resolve_outputpath(output, input)?;
+    output = rectify_bam_extension(output);
+    output = check_outputpath(output, force)?;
+    status!("Output will be saved to: {}", output.to_string_lossy());
+
+    let read_group = read_group.unwrap_or_else(|| {
+        let name = input.file_name().unwrap_or_default().to_string_lossy();
+        let sample = name
+            .split('.')
+            .next()
+            .filter(|s| !s.is_empty() && *s != "-");
+        ReadGroup::for_sample(sample.unwrap_or("sample"))
+    });
+    create_bam_writer(
+        output,
+        num_threads,
+        compression_level,
+        None,
+        read_group,
+        reads.len(),
+    )
 }
 
 ////////////////////////////////////////////////////////////////
 //  OTHER UTILITIES
 ////////////////////////////////////////////////////////////////
 
+// A path of "-" denotes stdin for inputs and stdout for outputs.
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+// Ensures that stdin and stdout are used by at most one input and one output each.
+pub fn check_stdio<'a>(
+    inputs: impl IntoIterator<Item = &'a PathBuf>,
+    outputs: impl IntoIterator<Item = &'a PathBuf>,
+) -> Result<()> {
+    if inputs.into_iter().filter(|path| is_stdio(path)).count() > 1 {
+        return Err(anyhow!(RuntimeErrors::MultipleStdio("input")));
+    }
+    if outputs.into_iter().filter(|path| is_stdio(path)).count() > 1 {
+        return Err(anyhow!(RuntimeErrors::MultipleStdio("output")));
+    }
+    Ok(())
+}
+
+// Uses the specified output path or derives one from the input path, which is impossible for stdin.
+pub fn resolve_outputpath(output: Option<PathBuf>, input: &Path) -> Result<PathBuf> {
+    match output {
+        Some(path) => Ok(path),
+        None if is_stdio(input) => Err(anyhow!(RuntimeErrors::OutputPathRequired)),
+        None => Ok(append_umi_to_path(input)),
+    }
+}
+
+// Like `resolve_outputpath`, but derived output paths get a custom suffix.
+pub fn resolve_outputpath_with_suffix(
+    output: Option<PathBuf>,
+    input: &Path,
+    suffix: &str,
+) -> Result<PathBuf> {
+    match output {
+        Some(path) => Ok(path),
+        None if is_stdio(input) => Err(anyhow!(RuntimeErrors::OutputPathRequired)),
+        None => Ok(append_suffix_to_path(input, suffix)),
+    }
+}
+
 // Checks whether an output path exists.
 pub fn check_outputpath(path: PathBuf, force: &bool) -> Result<PathBuf> {
-    // Skip overwrite prompt for "/dev/null" -> can/will be used for singletons.
-    if &path.to_string_lossy() == "/dev/null" {
+    // Skip overwrite prompt for "/dev/null" -> can/will be used for singletons. Stdout can't be overwritten.
+    if &path.to_string_lossy() == "/dev/null" || is_stdio(&path) {
         return Ok(path);
     }
 
@@ -151,7 +743,16 @@ fn prompt_overwrite(path: PathBuf) -> Result<PathBuf> {
 }
 
 // Checks whether an output path exists.
-pub fn rectify_extension(mut path: PathBuf, compress: &bool) -> Result<PathBuf> {
+pub fn rectify_extension(
+    mut path: PathBuf,
+    compress: &bool,
+    compression_format: &Option<CompressionFormat>,
+) -> Result<PathBuf> {
+    // Stdout has no extension to rectify.
+    if is_stdio(&path) {
+        return Ok(path);
+    }
+
     // Optional code, since compilation would fail on platforms that don't support FIFOs (Windows etc.)
     #[cfg(unix)]
     {
@@ -165,25 +766,50 @@ pub fn rectify_extension(mut path: PathBuf, compress: &bool) -> Result<PathBuf>
     }
 
     // handle the compression and adapt file extension if necessary.
+    let wanted = compression_format
+        .unwrap_or(CompressionFormat::Gzip)
+        .extension();
     if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
-        match (*compress, extension.ends_with("gz")) {
+        let compressed = extension.ends_with("gz") || extension == "zst";
+        match (*compress, compressed) {
             (true, false) => {
                 let mut new_extension = extension.to_owned();
-                new_extension.push_str(".gz");
+                new_extension.push('.');
+                new_extension.push_str(wanted);
                 path.set_extension(new_extension);
             }
+            // Replace the extension of the other compression format, but keep other names such as `.bgz`.
+            (true, true) if ["gz", "zst"].contains(&extension) && extension != wanted => {
+                path.set_extension(wanted);
+            }
             (false, true) => {
                 path.set_extension("");
             }
             _ => {}
         }
     } else if *compress {
-        path.set_extension("gz");
+        path.set_extension(wanted);
     }
     Ok(path)
 }
 
+// Replaces FastQ and compression extensions with `.bam`, e.g. of output paths derived from the input.
+pub fn rectify_bam_extension(mut path: PathBuf) -> PathBuf {
+    if is_stdio(&path) {
+        return path;
+    }
+    while let Some("gz" | "fq" | "fastq") = path.extension().and_then(|e| e.to_str()) {
+        path.set_extension("");
+    }
+    path.set_extension("bam");
+    path
+}
+
 pub fn append_umi_to_path(path: &Path) -> PathBuf {
+    append_suffix_to_path(path, "_with_UMIs")
+}
+
+pub fn append_suffix_to_path(path: &Path, suffix: &str) -> PathBuf {
     let path_str = path.as_os_str().to_string_lossy();
 
     let new_path_str = if path_str.contains('\\') || path_str.contains('/') {
@@ -193,12 +819,15 @@ pub fn append_umi_to_path(path: &Path) -> PathBuf {
         let re =
             Regex::new(r"(?P<path>^.*(?:\\|/))[^/\\]*?(?P<stem>\.?[^\.]+)\.(?P<extension>.*)$")
                 .unwrap();
-        let new_path_str = re.replace(&path_str, "${path}${stem}_with_UMIs.${extension}");
+        let new_path_str = re.replace(
+            &path_str,
+            format!("${{path}}${{stem}}{suffix}.${{extension}}"),
+        );
         new_path_str
     } else {
         // Simplified regex for the cases when the file name is given without any preceding path.
         let re = Regex::new(r"(?P<stem>^\.?[^\.]+)\.(?P<extension>.*)$").unwrap();
-        let new_path_str = re.replace(&path_str, "${stem}_with_UMIs.${extension}");
+        let new_path_str = re.replace(&path_str, format!("${{stem}}{suffix}.${{extension}}"));
         new_path_str
     };
     PathBuf::from(new_path_str.to_string())
@@ -262,29 +891,303 @@ mod tests {
     #[test]
     fn test_rectify_extension() {
         let p = PathBuf::from("test.fastq");
-        let result = rectify_extension(p, &false);
+        let result = rectify_extension(p, &false, &None);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), PathBuf::from("test.fastq"));
 
         let p = PathBuf::from("test.fastq");
-        let result = rectify_extension(p, &true);
+        let result = rectify_extension(p, &true, &None);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), PathBuf::from("test.fastq.gz"));
 
         let p = PathBuf::from("test");
-        let result = rectify_extension(p, &true);
+        let result = rectify_extension(p, &true, &None);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), PathBuf::from("test.gz"));
 
         let p = PathBuf::from("test.fastq.gz");
-        let result = rectify_extension(p, &false);
+        let result = rectify_extension(p, &false, &None);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), PathBuf::from("test.fastq"));
 
         let p = PathBuf::from("test.fastq.gz");
-        let result = rectify_extension(p, &true);
+        let result = rectify_extension(p, &true, &None);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), PathBuf::from("test.fastq.gz"));
+
+        let zstd = Some(CompressionFormat::Zstd);
+        let result = rectify_extension(PathBuf::from("test.fastq"), &true, &zstd);
+        assert_eq!(result.unwrap(), PathBuf::from("test.fastq.zst"));
+
+        let result = rectify_extension(PathBuf::from("test.fastq.gz"), &true, &zstd);
+        assert_eq!(result.unwrap(), PathBuf::from("test.fastq.zst"));
+
+        let result = rectify_extension(PathBuf::from("test.fastq.zst"), &false, &None);
+        assert_eq!(result.unwrap(), PathBuf::from("test.fastq"));
+
+        let result = rectify_extension(PathBuf::from("test.fastq.zst"), &true, &None);
+        assert_eq!(result.unwrap(), PathBuf::from("test.fastq.gz"));
+
+        // Other gzip extensions chosen by the user are kept.
+        let bgzf = Some(CompressionFormat::Bgzf);
+        let result = rectify_extension(PathBuf::from("out.fq.bgz"), &true, &bgzf);
+        assert_eq!(result.unwrap(), PathBuf::from("out.fq.bgz"));
+        let result = rectify_extension(PathBuf::from("out.fq.bgz"), &true, &None);
+        assert_eq!(result.unwrap(), PathBuf::from("out.fq.bgz"));
+    }
+
+    #[test]
+    fn test_detect_compression_from_magic_bytes() {
+        assert_eq!(
+            InputCompression::detect(&[0x1f, 0x8b, 0x08]),
+            InputCompression::Gzip
+        );
+        assert_eq!(
+            InputCompression::detect(&[0x28, 0xb5, 0x2f, 0xfd]),
+            InputCompression::Zstd
+        );
+        assert_eq!(
+            InputCompression::detect(b"BZh91AY"),
+            InputCompression::Bzip2
+        );
+        assert_eq!(InputCompression::detect(b"@read1"), InputCompression::None);
+        assert_eq!(InputCompression::detect(&[0x1f]), InputCompression::None);
+        assert_eq!(InputCompression::detect(&[]), InputCompression::None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_read_fastq_from_fifo() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let fifo = temp_dir.path().join("input.fq");
+        assert!(std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap()
+            .success());
+
+        let writer_path = fifo.clone();
+        let writer = std::thread::spawn(move || {
+            std::fs::write(writer_path, "@read1\nACGT\n+\nFFFF\n").unwrap();
+        });
+
+        let records: Vec<Record> = read_fastq(&fifo)
+            .unwrap()
+            .records()
+            .map(|r| r.unwrap())
+            .collect();
+        writer.join().unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].seq(), b"ACGT");
+    }
+
+    #[test]
+    fn test_lockstep_records_detects_truncated_input() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let long = temp_dir.path().join("long.fq");
+        let short = temp_dir.path().join("short.fq");
+        std::fs::write(&long, "@r1\nACGT\n+\nFFFF\n@r2\nACGT\n+\nFFFF\n").unwrap();
+        std::fs::write(&short, "@r1\nACGT\n+\nFFFF\n").unwrap();
+
+        let mut records = LockstepRecords::new(vec![
+            (long.clone(), read_fastq(&long).unwrap().records().into()),
+            (short.clone(), read_fastq(&short).unwrap().records().into()),
+        ]);
+        assert_eq!(records.next().unwrap().unwrap().len(), 2);
+        let err = records.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("short.fq ended after 1 records"));
+
+        let mut records = LockstepRecords::new(vec![
+            (long.clone(), read_fastq(&long).unwrap().records().into()),
+            (long.clone(), read_fastq(&long).unwrap().records().into()),
+        ]);
+        assert_eq!(records.by_ref().count(), 2);
+        assert!(records.next().is_none());
+    }
+
+    // Returns a single byte per read, like a slow pipe.
+    struct Trickle(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, into: &mut [u8]) -> std::io::Result<usize> {
+            let len = into.len().min(1);
+            self.0.read(&mut into[..len])
+        }
+    }
+
+    #[test]
+    fn test_read_start_collects_short_reads() {
+        let mut source = Trickle(std::io::Cursor::new(vec![0x28, 0xb5, 0x2f, 0xfd, 0x00]));
+        let start = read_start(&mut source, |start| start.len() >= 4).unwrap();
+        assert_eq!(InputCompression::detect(&start), InputCompression::Zstd);
+
+        // Streams shorter than the magic bytes end the peek at EOF.
+        let mut source = Trickle(std::io::Cursor::new(vec![0x1f]));
+        let start = read_start(&mut source, |start| start.len() >= 4).unwrap();
+        assert_eq!(start, vec![0x1f]);
+        assert_eq!(InputCompression::detect(&start), InputCompression::None);
+    }
+
+    #[test]
+    fn test_read_records_detects_sam() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let sam = temp_dir.path().join("reads.sam");
+        std::fs::write(
+            &sam,
+            "@HD\tVN:1.6\nr1\t77\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\tRX:Z:GG\n\
+             r1\t141\t*\t0\t0\t*\t*\t0\t0\tTTTT\tFFFF\tRX:Z:GG\n",
+        )
+        .unwrap();
+
+        let read2: Vec<Record> = read_records(&sam, InputRole::Read2)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(read2.len(), 1);
+        assert_eq!(read2[0].seq(), b"TTTT");
+
+        let umis: Vec<Record> = read_records(&sam, InputRole::Umi)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(umis.len(), 1);
+        assert_eq!(umis[0].seq(), b"GG");
+
+        let fastq = temp_dir.path().join("reads.fq");
+        std::fs::write(&fastq, "@r1\tx\nACGT\n+\nFFFF\n").unwrap();
+        let records: Vec<Record> = read_records(&fastq, InputRole::Umi)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records[0].seq(), b"ACGT");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_finish_reports_write_errors() {
+        // Writes to /dev/full fail, which must surface when the compressed stream is finished.
+        for format in [
+            CompressionFormat::Gzip,
+            CompressionFormat::Bgzf,
+            CompressionFormat::Zstd,
+        ] {
+            let mut writer = create_writer(
+                PathBuf::from("/dev/full"),
+                &true,
+                &Some(format),
+                &false,
+                &2,
+                &None,
+                None,
+            )
+            .unwrap();
+            writer
+                .write_record(Record::with_attrs("read1", None, b"ACGT", b"FFFF"))
+                .unwrap();
+            assert!(writer.finish().is_err());
+        }
+    }
+
+    #[test]
+    fn test_zstd_roundtrip() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let path = temp_dir.path().join("reads.fq.zst");
+        {
+            let mut writer = create_writer(
+                path.clone(),
+                &true,
+                &Some(CompressionFormat::Zstd),
+                &false,
+                &2,
+                &Some(19),
+                None,
+            )
+            .unwrap();
+            writer
+                .write_record(Record::with_attrs("read1", None, b"ACGT", b"FFFF"))
+                .unwrap();
+            writer.finish().unwrap();
+        }
+
+        assert!(std::fs::read(&path)
+            .unwrap()
+            .starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+        let records: Vec<Record> = read_fastq(&path)
+            .unwrap()
+            .records()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].seq(), b"ACGT");
+    }
+
+    #[test]
+    fn test_deinterleave() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let path = temp_dir.path().join("interleaved.fq");
+        std::fs::write(
+            &path,
+            "@r1/1\nAAAA\n+\nFFFF\n@r1/2\nCCCC\n+\nFFFF\n@r2/1\nGGGG\n+\nFFFF\n",
+        )
+        .unwrap();
+
+        let (mut read1, mut read2) = deinterleave(read_records(&path, InputRole::Other).unwrap());
+        // Mates may be consumed in any order.
+        assert_eq!(read2.next().unwrap().unwrap().id(), "r1/2");
+        assert_eq!(read1.next().unwrap().unwrap().id(), "r1/1");
+        assert_eq!(read1.next().unwrap().unwrap().id(), "r2/1");
+        assert!(read2.next().is_none());
+
+        // A missing second mate is reported like a truncated input.
+        let (read1, read2) = deinterleave(read_records(&path, InputRole::Other).unwrap());
+        let mut records = LockstepRecords::new(vec![(path.clone(), read1), (path.clone(), read2)]);
+        assert_eq!(records.next().unwrap().unwrap().len(), 2);
+        assert!(records.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_rectify_extension_keeps_stdout() {
+        let result = rectify_extension(PathBuf::from("-"), &true, &None);
+        assert_eq!(result.unwrap(), PathBuf::from("-"));
+    }
+
+    #[test]
+    fn test_rectify_bam_extension() {
+        let result = rectify_bam_extension(PathBuf::from("dir/read1_with_UMIs.fastq.gz"));
+        assert_eq!(result, PathBuf::from("dir/read1_with_UMIs.bam"));
+        assert_eq!(
+            rectify_bam_extension(PathBuf::from("out")),
+            PathBuf::from("out.bam")
+        );
+        assert_eq!(
+            rectify_bam_extension(PathBuf::from("out.bam")),
+            PathBuf::from("out.bam")
+        );
+        assert_eq!(
+            rectify_bam_extension(PathBuf::from("-")),
+            PathBuf::from("-")
+        );
+    }
+
+    #[test]
+    fn test_check_stdio() {
+        let stdio = PathBuf::from("-");
+        let file = PathBuf::from("test.fastq");
+        assert!(check_stdio([&stdio, &file], [&file, &stdio]).is_ok());
+        assert!(check_stdio([&stdio, &stdio], [&file]).is_err());
+        assert!(check_stdio([&file], [&stdio, &stdio]).is_err());
+    }
+
+    #[test]
+    fn test_resolve_outputpath() {
+        let result = resolve_outputpath(None, &PathBuf::from("test.fastq"));
+        assert_eq!(result.unwrap(), PathBuf::from("test_with_UMIs.fastq"));
+
+        let result = resolve_outputpath(Some(PathBuf::from("out.fq")), &PathBuf::from("-"));
+        assert_eq!(result.unwrap(), PathBuf::from("out.fq"));
+
+        assert!(resolve_outputpath(None, &PathBuf::from("-")).is_err());
     }
 
     #[test]
@@ -0,0 +1,186 @@
+////////////////////////////////////////////////////////////////
+//  GZI INDEX OF BGZF OUTPUT
+////////////////////////////////////////////////////////////////
+
+/*
+BGZF files consist of independent gzip blocks of at most 64 KiB, whose compressed size is stored in the BSIZE
+field of the block header and whose uncompressed size in the ISIZE field of the footer. A .gzi index, as written
+by `bgzip -i`, lists the compressed and uncompressed offsets of all blocks but the first, so that tools such as
+`samtools faidx` can seek into the file. The compressed stream is passed through to the file, while the block
+boundaries are recorded on the way. The compression runs on a separate thread, which owns the indexer, hence the
+entries are shared with a `GziIndex`, which writes the index once the stream has been finished.
+*/
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// Size of the BGZF block header up to and including the BSIZE field.
+const HEADER_SIZE: usize = 18;
+
+// Passes BGZF blocks through to the output and records their offsets.
+pub struct GziIndexer<W: Write> {
+    inner: W,
+    // Bytes of the current, incomplete block.
+    block: Vec<u8>,
+    compressed: u64,
+    uncompressed: u64,
+    index: GziIndex,
+}
+
+// Offsets of the blocks recorded by a `GziIndexer`, written to a separate file.
+#[derive(Clone)]
+pub struct GziIndex {
+    path: PathBuf,
+    entries: Arc<Mutex<Vec<(u64, u64)>>>,
+}
+
+impl<W: Write> GziIndexer<W> {
+    pub fn new(inner: W, index_path: PathBuf) -> Self {
+        GziIndexer {
+            inner,
+            block: Vec::new(),
+            compressed: 0,
+            uncompressed: 0,
+            index: GziIndex {
+                path: index_path,
+                entries: Arc::new(Mutex::new(Vec::new())),
+            },
+        }
+    }
+
+    // Handle to the recorded offsets, to write the index after the stream has been finished.
+    pub fn index(&self) -> GziIndex {
+        self.index.clone()
+    }
+
+    // Size of the current block, as soon as its header is complete.
+    fn block_size(&self) -> io::Result<Option<usize>> {
+        if self.block.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        if self.block[..4] != [0x1f, 0x8b, 0x08, 0x04] || self.block[12..14] != *b"BC" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Output is not BGZF-compressed and can't be indexed.",
+            ));
+        }
+        Ok(Some(
+            u16::from_le_bytes([self.block[16], self.block[17]]) as usize + 1,
+        ))
+    }
+
+    // Records all complete blocks. The EOF block holds no data and is not indexed.
+    fn index_blocks(&mut self) -> io::Result<()> {
+        while let Some(size) = self.block_size()? {
+            if self.block.len() < size {
+                break;
+            }
+            let isize = u32::from_le_bytes(self.block[size - 4..size].try_into().unwrap());
+            self.compressed += size as u64;
+            self.block.drain(..size);
+            if isize > 0 {
+                self.uncompressed += isize as u64;
+                self.index
+                    .entries
+                    .lock()
+                    .unwrap()
+                    .push((self.compressed, self.uncompressed));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl GziIndex {
+    // All offsets are stored as little-endian 64 bit integers, preceded by the number of entries.
+    pub fn write(&self) -> io::Result<()> {
+        let entries = self.entries.lock().unwrap();
+        let mut index = BufWriter::new(File::create(&self.path)?);
+        index.write_all(&(entries.len() as u64).to_le_bytes())?;
+        for (compressed, uncompressed) in entries.iter() {
+            index.write_all(&compressed.to_le_bytes())?;
+            index.write_all(&uncompressed.to_le_bytes())?;
+        }
+        index.flush()
+    }
+}
+
+impl<W: Write> Write for GziIndexer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.block.extend_from_slice(&buf[..written]);
+        self.index_blocks()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use gzp::{deflate::Bgzf, ZBuilder};
+    use std::io::Read;
+
+    #[test]
+    fn test_index_matches_blocks() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let path = temp_dir.path().join("reads.fq.gz");
+        let index_path = temp_dir.path().join("reads.fq.gz.gzi");
+
+        // Enough data for several blocks.
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let indexer = GziIndexer::new(File::create(&path).unwrap(), index_path.clone());
+        let index = indexer.index();
+        let mut writer = ZBuilder::<Bgzf, _>::new()
+            .num_threads(2)
+            .from_writer(indexer);
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+        index.write().unwrap();
+
+        let compressed = std::fs::read(&path).unwrap();
+        let mut decompressed = Vec::new();
+        flate2::read::MultiGzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        let index = std::fs::read(&index_path).unwrap();
+        let values: Vec<u64> = index
+            .chunks(8)
+            .map(|v| u64::from_le_bytes(v.try_into().unwrap()))
+            .collect();
+        let entries = values[0] as usize;
+        assert!(entries >= 3);
+        assert_eq!(values.len(), 1 + 2 * entries);
+
+        // Each entry points to the start of a block and the data before it.
+        for pair in values[1..].chunks(2) {
+            let (offset, position) = (pair[0] as usize, pair[1] as usize);
+            let mut block = Vec::new();
+            flate2::read::GzDecoder::new(&compressed[offset..])
+                .read_to_end(&mut block)
+                .unwrap();
+            assert_eq!(block, data[position..position + block.len()]);
+        }
+        // The last entry points to the EOF block.
+        assert_eq!(values[values.len() - 2] as usize, compressed.len() - 28);
+        assert_eq!(values[values.len() - 1] as usize, data.len());
+    }
+
+    #[test]
+    fn test_rejects_plain_gzip() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let mut indexer = GziIndexer::new(Vec::new(), temp_dir.path().join("reads.gzi"));
+        let err = indexer
+            .write(&[0x1f, 0x8b, 0x08, 0x00].repeat(5))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -6,13 +6,26 @@ use owo_colors::{OwoColorize, Stream::Stderr, Stream::Stdout};
 
 use std::process;
 
-use crate::auxiliary::timedrun;
+use crate::auxiliary::{reserve_stdout_for_data, status, stdout_is_data, timedrun};
+use crate::umi_comment::OptsComment;
 use crate::umi_external::OptsExternal;
-///use crate::umi_internal::OptsInternal;
+use crate::umi_internal::OptsInternal;
+use crate::umi_inverse::OptsInverse;
+use crate::umi_rewrite::OptsRewrite;
 mod auxiliary;
 mod file_io;
+mod gzi;
+mod read_id;
+mod read_structure;
+mod ubam;
+mod umi_comment;
 mod umi_errors;
 mod umi_external;
+mod umi_header;
+mod umi_index;
+mod umi_internal;
+mod umi_inverse;
+mod umi_rewrite;
 
 const LOGO: &str = r#"
 ░░░░░░░░░░░░░░░░░░░░░░░░░░░ SciLifeLab - National Genomics Infrastructure ░░░░░░░░░░░░░░░░░░░░░░░░░░░                                              
@@ -28,7 +41,7 @@ https://github.com/SciLifeLab/umi-transfer
     version = "1.5.0",
     author = "Written by Matthias Zepper, Judit Hohenthal & Johannes Alneberg",
     about = "A tool for transferring Unique Molecular Identifiers (UMIs).",
-    long_about = "Most tools capable of using UMIs to increase the accuracy of quantitative DNA sequencing experiments expect the respective UMI sequence to be embedded into the reads' IDs. You can use `umi-transfer external` to retrieve UMIs from a separate FastQ file and embed them to the IDs of your paired FastQ files."
+    long_about = "Most tools capable of using UMIs to increase the accuracy of quantitative DNA sequencing experiments expect the respective UMI sequence to be embedded into the reads' IDs. You can use `umi-transfer external` to retrieve UMIs from a separate FastQ file and embed them to the IDs of your paired FastQ files. If the UMIs are located at the start of the reads instead, `umi-transfer internal` will remove them from the sequences and embed them likewise."
 )]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
@@ -41,23 +54,46 @@ pub struct Opt {
 enum Subcommand {
     /// Integrate UMIs from a separate FastQ file.
     External(OptsExternal),
-    // Extract UMIs from the reads themselves.
-    // Internal(OptsInternal),
+    /// Extract UMIs from the reads themselves.
+    Internal(OptsInternal),
+    /// Move UMIs from the index field of the Illumina comment into the headers.
+    Comment(OptsComment),
+    /// Move UMIs from the headers into a separate FastQ file.
+    Inverse(OptsInverse),
+    /// Remove UMIs from the headers or embed them again with a new delimiter.
+    Rewrite(OptsRewrite),
 }
 
-fn main() {
-    println!(
+impl Subcommand {
+    fn writes_to_stdout(&self) -> bool {
+        match self {
+            Subcommand::External(arg) => arg.writes_to_stdout(),
+            Subcommand::Internal(arg) => arg.writes_to_stdout(),
+            Subcommand::Comment(arg) => arg.writes_to_stdout(),
+            Subcommand::Inverse(arg) => arg.writes_to_stdout(),
+            Subcommand::Rewrite(arg) => arg.writes_to_stdout(),
+        }
+    }
+}
+
+fn print_logo() {
+    // The logo must not end up in the sequence data, if the output is written to stdout.
+    let stream = if stdout_is_data() { Stderr } else { Stdout };
+    status!(
         "\n{}",
-        LOGO.if_supports_color(Stdout, |text| text.fg_rgb::<0xA7, 0xC9, 0x47>())
+        LOGO.if_supports_color(stream, |text| text.fg_rgb::<0xA7, 0xC9, 0x47>())
     );
     //println!("{}", WEB.fg_rgb::<0x49, 0x1F, 0x53>().italic());
-    println!(
+    status!(
         "{}",
-        WEB.if_supports_color(Stdout, |text| text.fg_rgb::<0x6F, 0x6F, 0x6F>())
+        WEB.if_supports_color(stream, |text| text.fg_rgb::<0x6F, 0x6F, 0x6F>())
     );
+}
 
+fn main() {
     // for custom styles of clap parsing errors and help message
     let opt: Opt = Opt::try_parse().unwrap_or_else(|err| {
+        print_logo();
         match err.kind() {
             // rust render as Clap would by default.
             clap::error::ErrorKind::DisplayHelp
@@ -78,11 +114,22 @@ fn main() {
         };
     });
 
+    if opt.cmd.writes_to_stdout() {
+        reserve_stdout_for_data();
+    }
+    print_logo();
+
     timedrun("umi-transfer finished", || {
         let res = match opt.cmd {
             Subcommand::External(arg) => {
                 umi_external::run(arg).context("Failed to include the UMIs")
-            } //Subcommand::Internal(arg) => umi_internal::run(arg),
+            }
+            Subcommand::Internal(arg) => {
+                umi_internal::run(arg).context("Failed to extract the UMIs")
+            }
+            Subcommand::Comment(arg) => umi_comment::run(arg).context("Failed to move the UMIs"),
+            Subcommand::Inverse(arg) => umi_inverse::run(arg).context("Failed to remove the UMIs"),
+            Subcommand::Rewrite(arg) => umi_rewrite::run(arg).context("Failed to rewrite the UMIs"),
         };
 
         if let Err(err) = res {
@@ -0,0 +1,213 @@
+////////////////////////////////////////////////////////////////
+//  READ ID NORMALISATION
+////////////////////////////////////////////////////////////////
+
+/*
+Read IDs of R1, R2 and the UMI read are expected to be identical. Older CASAVA versions, SRA dumps and
+other converters however add mate suffixes or prefixes, which would otherwise cause a mismatch.
+The rules are applied cumulatively in the given order until both IDs match.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum IdRule {
+    // Strip `/1`, `/2`, `/3` suffixes and SRA-style `.1`, `.2`, `.3` suffixes.
+    MateSuffix,
+    // Strip the SRA accession and spot number prefix, e.g. `SRR001666.1.`.
+    SraPrefix,
+    // Compare only lane, tile and the x and y coordinates of Illumina read IDs.
+    Coordinates,
+}
+
+impl IdRule {
+    pub fn apply<'a>(&self, id: &'a str) -> &'a str {
+        match self {
+            IdRule::MateSuffix => strip_mate_suffix(id),
+            IdRule::SraPrefix => strip_sra_prefix(id),
+            IdRule::Coordinates => illumina_coordinates(id),
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            IdRule::MateSuffix => "stripping mate suffixes",
+            IdRule::SraPrefix => "stripping SRA accession prefixes",
+            IdRule::Coordinates => "comparing Illumina coordinates",
+        }
+    }
+}
+
+fn strip_mate_suffix(id: &str) -> &str {
+    match id.rsplit_once(['/', '.']) {
+        Some((stem, "1" | "2" | "3")) => {
+            // A dot is only treated as mate separator, if the stem is an SRA-style `accession.spot` itself.
+            if id.as_bytes()[stem.len()] == b'/' || stem.contains('.') {
+                stem
+            } else {
+                id
+            }
+        }
+        _ => id,
+    }
+}
+
+fn strip_sra_prefix(id: &str) -> &str {
+    let bytes = id.as_bytes();
+    let is_accession = bytes.len() > 3
+        && matches!(bytes[0], b'S' | b'E' | b'D')
+        && bytes[1..3].eq(b"RR")
+        && bytes[3].is_ascii_digit();
+    if !is_accession {
+        return id;
+    }
+    // Accession and spot number, followed by the original read name: `SRR001666.1.<name>`
+    let mut fields = id.splitn(3, '.');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(_), Some(spot), Some(name))
+            if !spot.is_empty() && spot.bytes().all(|b| b.is_ascii_digit()) && !name.is_empty() =>
+        {
+            name
+        }
+        _ => id,
+    }
+}
+
+fn illumina_coordinates(id: &str) -> &str {
+    // Remove index and read number of old CASAVA formats: `HWUSI-EAS100R:6:73:941:1973#0/1`
+    let id = id.split(['#', '/']).next().unwrap_or(id);
+    let colons: Vec<usize> = id.match_indices(':').map(|(i, _)| i).collect();
+    match colons.len() {
+        // CASAVA 1.8: instrument:run:flowcell:lane:tile:x:y
+        6 => &id[colons[2] + 1..],
+        // Older CASAVA: instrument:lane:tile:x:y
+        4 => &id[colons[0] + 1..],
+        _ => id,
+    }
+}
+
+// Applies all rules in the given order to obtain a canonical read ID, e.g. for indexing.
+pub fn normalise<'a>(id: &'a str, rules: &[IdRule]) -> &'a str {
+    rules.iter().fold(id, |id, rule| rule.apply(id))
+}
+
+// Compares read IDs and keeps track of how many IDs only matched after applying a rule.
+pub struct IdMatcher {
+    rules: Vec<IdRule>,
+    matched_by_rule: Vec<usize>,
+}
+
+impl IdMatcher {
+    pub fn new(rules: Vec<IdRule>) -> Self {
+        let matched_by_rule = vec![0; rules.len()];
+        IdMatcher {
+            rules,
+            matched_by_rule,
+        }
+    }
+
+    pub fn matches(&mut self, a: &str, b: &str) -> bool {
+        if a == b {
+            return true;
+        }
+        let (mut a, mut b) = (a, b);
+        for (i, rule) in self.rules.iter().enumerate() {
+            (a, b) = (rule.apply(a), rule.apply(b));
+            if a == b {
+                self.matched_by_rule[i] += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    // Summary of all rules that were needed to match read IDs.
+    pub fn report(&self) -> Vec<String> {
+        self.rules
+            .iter()
+            .zip(&self.matched_by_rule)
+            .filter(|(_, count)| **count > 0)
+            .map(|(rule, count)| format!("{} read IDs matched after {}", count, rule.description()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_strip_mate_suffix() {
+        assert_eq!(
+            strip_mate_suffix("HWUSI-EAS100R:6:73:941:1973#0/1"),
+            "HWUSI-EAS100R:6:73:941:1973#0"
+        );
+        assert_eq!(strip_mate_suffix("read/3"), "read");
+        assert_eq!(strip_mate_suffix("SRR001666.1.2"), "SRR001666.1");
+        // The spot number of SRA IDs is no mate suffix.
+        assert_eq!(strip_mate_suffix("SRR001666.1"), "SRR001666.1");
+        assert_eq!(strip_mate_suffix("read/4"), "read/4");
+        assert_eq!(strip_mate_suffix("read"), "read");
+    }
+
+    #[test]
+    fn test_strip_sra_prefix() {
+        assert_eq!(
+            strip_sra_prefix("SRR001666.1.HWUSI-EAS100R:6:73:941:1973"),
+            "HWUSI-EAS100R:6:73:941:1973"
+        );
+        assert_eq!(strip_sra_prefix("ERR123.42.read"), "read");
+        assert_eq!(strip_sra_prefix("SRR001666.1"), "SRR001666.1");
+        assert_eq!(strip_sra_prefix("SRRX.1.read"), "SRRX.1.read");
+        assert_eq!(
+            strip_sra_prefix("SCILIFELAB:500:NGISTLM"),
+            "SCILIFELAB:500:NGISTLM"
+        );
+    }
+
+    #[test]
+    fn test_illumina_coordinates() {
+        assert_eq!(
+            illumina_coordinates("SCILIFELAB:500:NGISTLM:1:1101:19994:1016"),
+            "1:1101:19994:1016"
+        );
+        assert_eq!(
+            illumina_coordinates("HWUSI-EAS100R:6:73:941:1973#0/1"),
+            "6:73:941:1973"
+        );
+        assert_eq!(illumina_coordinates("read_1"), "read_1");
+    }
+
+    #[test]
+    fn test_matcher_applies_rules_cumulatively() {
+        let mut matcher = IdMatcher::new(vec![IdRule::MateSuffix, IdRule::SraPrefix]);
+        assert!(matcher.matches("read", "read"));
+        assert!(matcher.matches("read/1", "read/3"));
+        assert!(matcher.matches("SRR1.1.read/1", "read"));
+        assert!(!matcher.matches("read/1", "other/1"));
+        assert_eq!(
+            matcher.report(),
+            [
+                "1 read IDs matched after stripping mate suffixes",
+                "1 read IDs matched after stripping SRA accession prefixes"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matcher_without_rules_is_strict() {
+        let mut matcher = IdMatcher::new(vec![]);
+        assert!(!matcher.matches("read/1", "read/2"));
+        assert!(matcher.report().is_empty());
+    }
+
+    #[test]
+    fn test_normalise() {
+        let rules = [IdRule::MateSuffix, IdRule::Coordinates];
+        assert_eq!(
+            normalise("INSTR:500:FLOWCELL:1:1101:19994:1016/2", &rules),
+            "1:1101:19994:1016"
+        );
+        assert_eq!(normalise("read/2", &[]), "read/2");
+    }
+}
@@ -0,0 +1,330 @@
+use anyhow::{anyhow, Result};
+use bio::io::fastq::Record;
+use std::{fmt, str::FromStr};
+
+use crate::umi_errors::RuntimeErrors;
+
+////////////////////////////////////////////////////////////////
+//  READ STRUCTURES
+////////////////////////////////////////////////////////////////
+
+/*
+Read structures describe which bases of a read belong to which segment, following the conventions of fgbio:
+Each segment consists of a length and a type, e.g. `8M2S+T` denotes an 8 base UMI (M), followed by 2 skipped
+bases (S) and a template (T) of variable length (+). A `+` is allowed for at most one segment per read structure.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    Template,
+    MolecularBarcode,
+    SampleBarcode,
+    CellularBarcode,
+    Skip,
+}
+
+impl SegmentKind {
+    fn from_symbol(symbol: char) -> Option<Self> {
+        match symbol {
+            'T' => Some(SegmentKind::Template),
+            'M' => Some(SegmentKind::MolecularBarcode),
+            'B' => Some(SegmentKind::SampleBarcode),
+            'C' => Some(SegmentKind::CellularBarcode),
+            'S' => Some(SegmentKind::Skip),
+            _ => None,
+        }
+    }
+
+    fn symbol(&self) -> char {
+        match self {
+            SegmentKind::Template => 'T',
+            SegmentKind::MolecularBarcode => 'M',
+            SegmentKind::SampleBarcode => 'B',
+            SegmentKind::CellularBarcode => 'C',
+            SegmentKind::Skip => 'S',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    // None denotes a segment of variable length (`+`).
+    pub length: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadStructure {
+    segments: Vec<Segment>,
+}
+
+// Sequence and quality of the UMI and template segments of a single record.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExtractedSegments {
+    pub umi_seq: Vec<u8>,
+    pub umi_qual: Vec<u8>,
+    pub template_seq: Vec<u8>,
+    pub template_qual: Vec<u8>,
+}
+
+impl ReadStructure {
+    // Read structure for a fixed-length UMI at the 5' end, followed by the template.
+    pub fn inline_umi(umi_len: usize) -> Self {
+        let mut segments = Vec::with_capacity(2);
+        if umi_len > 0 {
+            segments.push(Segment {
+                kind: SegmentKind::MolecularBarcode,
+                length: Some(umi_len),
+            });
+        }
+        segments.push(Segment {
+            kind: SegmentKind::Template,
+            length: None,
+        });
+        ReadStructure { segments }
+    }
+
+    // Read structure selecting `umi_len` bases from the 1-based position `start` as UMI and skipping all others.
+    // Without a length, all bases from `start` onwards are used.
+    pub fn umi_range(start: usize, umi_len: Option<usize>) -> Self {
+        let mut segments = Vec::with_capacity(3);
+        if start > 1 {
+            segments.push(Segment {
+                kind: SegmentKind::Skip,
+                length: Some(start - 1),
+            });
+        }
+        segments.push(Segment {
+            kind: SegmentKind::MolecularBarcode,
+            length: umi_len,
+        });
+        if umi_len.is_some() {
+            segments.push(Segment {
+                kind: SegmentKind::Skip,
+                length: None,
+            });
+        }
+        ReadStructure { segments }
+    }
+
+    // Pads a record that is too short for this read structure with `N` bases of quality 0.
+    pub fn pad(&self, record: &Record) -> Record {
+        let missing = self.fixed_length().saturating_sub(record.seq().len());
+        let mut seq = record.seq().to_vec();
+        let mut qual = record.qual().to_vec();
+        seq.resize(seq.len() + missing, b'N');
+        qual.resize(qual.len() + missing, b'!');
+        Record::with_attrs(record.id(), record.desc(), &seq, &qual)
+    }
+
+    // Total number of bases covered by the segments of fixed length.
+    pub fn fixed_length(&self) -> usize {
+        self.segments.iter().filter_map(|s| s.length).sum()
+    }
+
+    pub fn has_variable_segment(&self) -> bool {
+        self.segments.iter().any(|s| s.length.is_none())
+    }
+
+    // Checks whether a read of the given length can be split according to this read structure.
+    pub fn fits(&self, read_length: usize) -> bool {
+        if self.has_variable_segment() {
+            read_length >= self.fixed_length()
+        } else {
+            read_length == self.fixed_length()
+        }
+    }
+
+    // Splits a record into its UMI and template segments. Skip and other barcode segments are discarded.
+    pub fn extract(&self, record: &Record) -> Result<ExtractedSegments> {
+        let read_length = record.seq().len();
+        if !self.fits(read_length) {
+            return Err(anyhow!(RuntimeErrors::ReadStructureMismatch(
+                record.id().to_string(),
+                read_length,
+                self.to_string()
+            )));
+        }
+
+        let variable_length = read_length - self.fixed_length();
+        let mut extracted = ExtractedSegments::default();
+        let mut start = 0;
+
+        for segment in &self.segments {
+            let end = start + segment.length.unwrap_or(variable_length);
+            // Quality strings are clamped rather than indexed, so that malformed records do not cause a panic.
+            let qual_len = record.qual().len();
+            let (seq, qual) = (
+                &record.seq()[start..end],
+                &record.qual()[start.min(qual_len)..end.min(qual_len)],
+            );
+            match segment.kind {
+                SegmentKind::MolecularBarcode => {
+                    extracted.umi_seq.extend_from_slice(seq);
+                    extracted.umi_qual.extend_from_slice(qual);
+                }
+                SegmentKind::Template => {
+                    extracted.template_seq.extend_from_slice(seq);
+                    extracted.template_qual.extend_from_slice(qual);
+                }
+                _ => {}
+            }
+            start = end;
+        }
+        Ok(extracted)
+    }
+}
+
+impl FromStr for ReadStructure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut length = String::new();
+
+        for symbol in s.trim().to_uppercase().chars() {
+            if symbol.is_ascii_digit() || symbol == '+' {
+                length.push(symbol);
+                continue;
+            }
+            let kind = SegmentKind::from_symbol(symbol).ok_or_else(|| {
+                format!(
+                    "Invalid segment type '{symbol}' in read structure {s}. Use T, M, B, C or S."
+                )
+            })?;
+            let length = match std::mem::take(&mut length).as_str() {
+                "" => {
+                    return Err(format!(
+                        "Missing segment length before '{symbol}' in read structure {s}."
+                    ))
+                }
+                "+" => None,
+                digits => match digits.parse::<usize>() {
+                    _ if digits.contains('+') => {
+                        return Err(format!(
+                            "Invalid segment length '{digits}' in read structure {s}."
+                        ))
+                    }
+                    Ok(0) | Err(_) => {
+                        return Err(format!(
+                            "Invalid segment length '{digits}' in read structure {s}."
+                        ))
+                    }
+                    Ok(n) => Some(n),
+                },
+            };
+            segments.push(Segment { kind, length });
+        }
+
+        if !length.is_empty() {
+            return Err(format!("Read structure {s} must end with a segment type."));
+        }
+        if segments.is_empty() {
+            return Err("Read structure must not be empty.".to_string());
+        }
+        if segments.iter().filter(|s| s.length.is_none()).count() > 1 {
+            return Err(format!(
+                "Read structure {s} may only contain one segment of variable length (+)."
+            ));
+        }
+        Ok(ReadStructure { segments })
+    }
+}
+
+impl fmt::Display for ReadStructure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in &self.segments {
+            match segment.length {
+                Some(length) => write!(f, "{}{}", length, segment.kind.symbol())?,
+                None => write!(f, "+{}", segment.kind.symbol())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn record(seq: &[u8]) -> Record {
+        let qual: Vec<u8> = (0..seq.len()).map(|i| b'A' + i as u8).collect();
+        Record::with_attrs("read", None, seq, &qual)
+    }
+
+    #[test]
+    fn test_parse_and_display_read_structures() {
+        for text in ["8M2S+T", "12M+T", "+T", "8B4M", "+M", "4S+M2S"] {
+            let rs: ReadStructure = text.parse().unwrap();
+            assert_eq!(rs.to_string(), text);
+        }
+        let rs: ReadStructure = "8m2s+t".parse().unwrap();
+        assert_eq!(rs.to_string(), "8M2S+T");
+        assert_eq!(rs.fixed_length(), 10);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_read_structures() {
+        for text in ["", "8", "8X", "M", "0M+T", "+M+T", "8M2S+", "8M-2S", "+8M"] {
+            assert!(text.parse::<ReadStructure>().is_err(), "{text} was parsed");
+        }
+    }
+
+    #[test]
+    fn test_inline_umi_matches_parsed_structure() {
+        assert_eq!(ReadStructure::inline_umi(8), "8M+T".parse().unwrap());
+        assert_eq!(ReadStructure::inline_umi(0), "+T".parse().unwrap());
+    }
+
+    #[test]
+    fn test_umi_range_matches_parsed_structure() {
+        assert_eq!(
+            ReadStructure::umi_range(1, Some(8)),
+            "8M+S".parse().unwrap()
+        );
+        assert_eq!(
+            ReadStructure::umi_range(3, Some(8)),
+            "2S8M+S".parse().unwrap()
+        );
+        assert_eq!(ReadStructure::umi_range(3, None), "2S+M".parse().unwrap());
+    }
+
+    #[test]
+    fn test_pad_short_record() {
+        let rs: ReadStructure = "2S4M+S".parse().unwrap();
+        let padded = rs.pad(&record(b"ACGT"));
+        assert_eq!(padded.seq(), b"ACGTNN");
+        assert_eq!(padded.qual(), b"ABCD!!");
+        assert_eq!(rs.extract(&padded).unwrap().umi_seq, b"GTNN");
+    }
+
+    #[test]
+    fn test_extract_splits_umi_and_template() {
+        let rs: ReadStructure = "4M2S+T".parse().unwrap();
+        let extracted = rs.extract(&record(b"ACGTNNGGCC")).unwrap();
+        assert_eq!(extracted.umi_seq, b"ACGT");
+        assert_eq!(extracted.umi_qual, b"ABCD");
+        assert_eq!(extracted.template_seq, b"GGCC");
+        assert_eq!(extracted.template_qual, b"GHIJ");
+    }
+
+    #[test]
+    fn test_extract_variable_segment_in_the_middle() {
+        let rs: ReadStructure = "2M+S2M".parse().unwrap();
+        let extracted = rs.extract(&record(b"ACNNNNGT")).unwrap();
+        assert_eq!(extracted.umi_seq, b"ACGT");
+        assert!(extracted.template_seq.is_empty());
+    }
+
+    #[test]
+    fn test_extract_checks_read_length() {
+        let rs: ReadStructure = "8M2S+T".parse().unwrap();
+        assert!(rs.extract(&record(b"ACGTACGTN")).is_err());
+        assert!(rs.extract(&record(b"ACGTACGTNN")).is_ok());
+
+        let rs: ReadStructure = "8M".parse().unwrap();
+        assert!(rs.extract(&record(b"ACGTACGTA")).is_err());
+        assert!(rs.extract(&record(b"ACGTACGT")).is_ok());
+    }
+}
@@ -0,0 +1,728 @@
+////////////////////////////////////////////////////////////////
+//  UNALIGNED BAM ENCODING AND DECODING
+////////////////////////////////////////////////////////////////
+
+/*
+Pipelines built on fgbio or GATK start from unaligned BAM files instead of FastQ. Records are encoded as
+unmapped reads without reference sequences, and paired reads are written as consecutive mates to the same
+file. SAM-style tags in the description, such as the RX and QX tags of the UMI, become BAM tags, while the
+remaining comment is dropped as by Picard's FastqToSam. Every record is assigned to the read group of the
+header. The encoded records are compressed as BGZF blocks by the output writer.
+
+Conversely, unaligned SAM and BAM files are decoded into FastQ records, so they can serve as input. Reads of
+paired files are selected by their mate flags. The BC tag provides the index of the Illumina comment and OQ
+the original base qualities. For UMI inputs, the RX and QX tags take precedence over the read sequence.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use bio::{alphabets::dna::revcomp, io::fastq::Record};
+use std::io::{BufRead, Read};
+use std::str::FromStr;
+
+use crate::umi_errors::RuntimeErrors;
+
+// SAM flags of unmapped reads.
+const FLAG_PAIRED: u16 = 0x1;
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_MATE_UNMAPPED: u16 = 0x8;
+const FLAG_FIRST: u16 = 0x40;
+const FLAG_SECOND: u16 = 0x80;
+const FLAG_REVERSE: u16 = 0x10;
+const FLAG_QC_FAIL: u16 = 0x200;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+// Bin of unmapped reads without a position, reg2bin(-1, 0).
+const UNMAPPED_BIN: u16 = 4680;
+
+// Size of the fixed fields of a BAM record and an upper bound for the size of whole records, which protects from
+// allocating huge buffers for corrupted input.
+const MIN_RECORD_SIZE: i32 = 32;
+const MAX_RECORD_SIZE: i32 = 1 << 26;
+
+// The `@RG` header line and the ID assigned to all records, e.g. `ID:A\tSM:sample1\tPL:ILLUMINA`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadGroup {
+    id: String,
+    fields: Vec<(String, String)>,
+}
+
+impl ReadGroup {
+    // Default read group with the ID `A`, named after the sample.
+    pub fn for_sample(sample: &str) -> Self {
+        ReadGroup {
+            id: "A".to_string(),
+            fields: vec![("SM".to_string(), sample.to_string())],
+        }
+    }
+
+    fn header_line(&self) -> String {
+        let mut line = format!("@RG\tID:{}", self.id);
+        for (tag, value) in &self.fields {
+            line.push_str(&format!("\t{tag}:{value}"));
+        }
+        line
+    }
+}
+
+impl FromStr for ReadGroup {
+    type Err = String;
+
+    // Fields are separated by tabs or literal `\t` as for `bwa mem -R`. A leading `@RG` is optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let line = s.replace("\\t", "\t");
+        let mut id = None;
+        let mut fields = Vec::new();
+        for field in line
+            .split('\t')
+            .filter(|field| !field.is_empty() && *field != "@RG")
+        {
+            match field.split_once(':') {
+                Some(("ID", value)) if !value.is_empty() => id = Some(value.to_string()),
+                Some((tag, value)) if tag.len() == 2 && !value.is_empty() => {
+                    fields.push((tag.to_string(), value.to_string()))
+                }
+                _ => {
+                    return Err(format!(
+                    "Invalid field {field} in read group {s}. Fields must look like `SM:sample`."
+                ))
+                }
+            }
+        }
+        let id = id.ok_or_else(|| format!("Read group {s} lacks an `ID:` field."))?;
+        Ok(ReadGroup { id, fields })
+    }
+}
+
+// Binary BAM header without reference sequences.
+pub fn header(read_group: &ReadGroup) -> Vec<u8> {
+    let text = format!(
+        "@HD\tVN:1.6\tSO:unsorted\n{}\n@PG\tID:umi-transfer\tPN:umi-transfer\tVN:{}\n",
+        read_group.header_line(),
+        env!("CARGO_PKG_VERSION")
+    );
+    let mut out = Vec::with_capacity(text.len() + 12);
+    out.extend_from_slice(b"BAM\x01");
+    out.extend_from_slice(&(text.len() as i32).to_le_bytes());
+    out.extend_from_slice(text.as_bytes());
+    out.extend_from_slice(&0i32.to_le_bytes());
+    out
+}
+
+// SAM flag of the `mate`-th read (counted from 0) of fragments with `mates` reads.
+pub fn flag(mate: usize, mates: usize) -> u16 {
+    match (mates, mate) {
+        (1, _) => FLAG_UNMAPPED,
+        (_, 0) => FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_FIRST,
+        _ => FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | FLAG_SECOND,
+    }
+}
+
+// Encodes a FastQ record as an unmapped BAM record.
+pub fn encode(record: &Record, flag: u16, read_group: &ReadGroup) -> Result<Vec<u8>> {
+    let name = record.id().as_bytes();
+    if name.is_empty() || name.len() > 254 {
+        return Err(anyhow!(
+            "Read name {} must have between 1 and 254 characters for BAM output.",
+            record.id()
+        ));
+    }
+    let (seq, qual) = (record.seq(), record.qual());
+    if seq.len() != qual.len() {
+        return Err(anyhow!(RuntimeErrors::QualityLengthMismatch(
+            record.id().to_string(),
+            seq.len(),
+            qual.len()
+        )));
+    }
+
+    let mut out = Vec::with_capacity(64 + name.len() + seq.len() * 2);
+    // The block size is filled in at the end.
+    out.extend_from_slice(&0i32.to_le_bytes());
+    out.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+    out.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+    out.push(name.len() as u8 + 1);
+    out.push(0); // mapq
+    out.extend_from_slice(&UNMAPPED_BIN.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+    out.extend_from_slice(&flag.to_le_bytes());
+    out.extend_from_slice(&(seq.len() as i32).to_le_bytes());
+    out.extend_from_slice(&(-1i32).to_le_bytes()); // next refID
+    out.extend_from_slice(&(-1i32).to_le_bytes()); // next pos
+    out.extend_from_slice(&0i32.to_le_bytes()); // tlen
+    out.extend_from_slice(name);
+    out.push(0);
+    for pair in seq.chunks(2) {
+        let high = base_code(pair[0]);
+        let low = pair.get(1).map_or(0, |&base| base_code(base));
+        out.push(high << 4 | low);
+    }
+    out.extend(qual.iter().map(|q| q.saturating_sub(33)));
+
+    for field in record.desc().unwrap_or_default().split('\t') {
+        if let Some(tag) = parse_tag(field) {
+            tag.encode(&mut out);
+        }
+    }
+    SamTag::String(*b"RG", &read_group.id).encode(&mut out);
+
+    let block_size = (out.len() - 4) as i32;
+    out[..4].copy_from_slice(&block_size.to_le_bytes());
+    Ok(out)
+}
+
+// 4-bit code of a base, unknown characters are encoded as N.
+fn base_code(base: u8) -> u8 {
+    b"=ACMGRSVTWYHKDBN"
+        .iter()
+        .position(|&code| code == base.to_ascii_uppercase())
+        .unwrap_or(15) as u8
+}
+
+// SAM-style tags of the description, which are carried over into BAM records.
+#[derive(Debug, PartialEq)]
+enum SamTag<'a> {
+    Char([u8; 2], u8),
+    Int([u8; 2], i32),
+    Float([u8; 2], f32),
+    String([u8; 2], &'a str),
+    Hex([u8; 2], &'a str),
+}
+
+impl SamTag<'_> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            SamTag::Char(tag, value) => {
+                out.extend_from_slice(tag);
+                out.extend_from_slice(&[b'A', *value]);
+            }
+            SamTag::Int(tag, value) => {
+                out.extend_from_slice(tag);
+                out.push(b'i');
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            SamTag::Float(tag, value) => {
+                out.extend_from_slice(tag);
+                out.push(b'f');
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            SamTag::String(tag, value) => {
+                out.extend_from_slice(tag);
+                out.push(b'Z');
+                out.extend_from_slice(value.as_bytes());
+                out.push(0);
+            }
+            SamTag::Hex(tag, value) => {
+                out.extend_from_slice(tag);
+                out.push(b'H');
+                out.extend_from_slice(value.as_bytes());
+                out.push(0);
+            }
+        }
+    }
+}
+
+// Parses `TG:T:value` fields, anything else such as the CASAVA comment is no tag.
+fn parse_tag(field: &str) -> Option<SamTag<'_>> {
+    let bytes = field.as_bytes();
+    if bytes.len() < 5
+        || !bytes[0].is_ascii_alphabetic()
+        || !bytes[1].is_ascii_alphanumeric()
+        || bytes[2] != b':'
+        || bytes[4] != b':'
+    {
+        return None;
+    }
+    let tag = [bytes[0], bytes[1]];
+    let value = &field[5..];
+    match bytes[3] {
+        b'A' if value.len() == 1 => Some(SamTag::Char(tag, value.as_bytes()[0])),
+        b'i' => value.parse().ok().map(|value| SamTag::Int(tag, value)),
+        b'f' => value.parse().ok().map(|value| SamTag::Float(tag, value)),
+        b'Z' => Some(SamTag::String(tag, value)),
+        b'H' => Some(SamTag::Hex(tag, value)),
+        _ => None,
+    }
+}
+
+////////////////////////////////////////////////////////////////
+//  SAM AND BAM INPUT
+////////////////////////////////////////////////////////////////
+
+// Which records of a SAM or BAM file an input provides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputRole {
+    // First mates and unpaired reads.
+    Read1,
+    // Second mates and unpaired reads.
+    Read2,
+    // Like `Read1`, but the UMI is taken from the RX and QX tags, if present.
+    Umi,
+    // All records.
+    Other,
+}
+
+// A decoded SAM or BAM record. Tag values are kept as text, as in SAM files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SamRecord {
+    name: String,
+    flag: u16,
+    seq: Vec<u8>,
+    // Phred+33 encoded qualities, empty if missing.
+    qual: Vec<u8>,
+    tags: Vec<([u8; 2], String)>,
+}
+
+impl SamRecord {
+    fn tag(&self, tag: &[u8; 2]) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Converts the record to a FastQ record, or returns None if it does not belong to the input.
+    pub fn into_fastq(self, role: InputRole) -> Option<Record> {
+        let excluded = FLAG_SECONDARY
+            | FLAG_SUPPLEMENTARY
+            | match role {
+                InputRole::Read1 | InputRole::Umi => FLAG_SECOND,
+                InputRole::Read2 => FLAG_FIRST,
+                InputRole::Other => 0,
+            };
+        if self.flag & excluded != 0 {
+            return None;
+        }
+
+        // The BC tag holds the sample index, which is otherwise part of the Illumina comment.
+        let desc = self.tag(b"BC").map(|index| {
+            let read_nr = if self.flag & FLAG_SECOND != 0 { 2 } else { 1 };
+            let filtered = if self.flag & FLAG_QC_FAIL != 0 {
+                'Y'
+            } else {
+                'N'
+            };
+            format!("{read_nr}:{filtered}:0:{index}")
+        });
+
+        if role == InputRole::Umi {
+            if let Some(umi) = self.tag(b"RX") {
+                let qual = match self.tag(b"QX") {
+                    Some(qual) if qual.len() == umi.len() => qual.as_bytes().to_vec(),
+                    _ => vec![b'I'; umi.len()],
+                };
+                return Some(Record::with_attrs(
+                    &self.name,
+                    desc.as_deref(),
+                    umi.as_bytes(),
+                    &qual,
+                ));
+            }
+        }
+
+        // Original qualities take precedence over recalibrated ones. Missing qualities are set to the minimum.
+        let mut qual = match self.tag(b"OQ") {
+            Some(oq) if oq.len() == self.seq.len() => oq.as_bytes().to_vec(),
+            _ if self.qual.len() == self.seq.len() => self.qual.clone(),
+            _ => vec![b'!'; self.seq.len()],
+        };
+        // Reads on the reverse strand are stored reverse complemented.
+        let seq = if self.flag & FLAG_REVERSE != 0 {
+            qual.reverse();
+            revcomp(&self.seq)
+        } else {
+            self.seq
+        };
+        Some(Record::with_attrs(&self.name, desc.as_deref(), &seq, &qual))
+    }
+}
+
+// Parses a SAM alignment line. Tags of array type are skipped.
+pub fn parse_sam_line(line: &str) -> Result<SamRecord> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 11 {
+        return Err(anyhow!(
+            "SAM record has {} instead of at least 11 fields: {}",
+            fields.len(),
+            line
+        ));
+    }
+    let flag = fields[1]
+        .parse()
+        .with_context(|| format!("Invalid flag of SAM record {}", fields[0]))?;
+    let seq = match fields[9] {
+        "*" => Vec::new(),
+        seq => seq.as_bytes().to_vec(),
+    };
+    let qual = match fields[10] {
+        "*" => Vec::new(),
+        qual => qual.as_bytes().to_vec(),
+    };
+    let tags = fields[11..]
+        .iter()
+        .filter_map(|field| {
+            let bytes = field.as_bytes();
+            (bytes.len() >= 5 && bytes[2] == b':' && bytes[4] == b':' && bytes[3] != b'B')
+                .then(|| ([bytes[0], bytes[1]], field[5..].to_string()))
+        })
+        .collect();
+    Ok(SamRecord {
+        name: fields[0].to_string(),
+        flag,
+        seq,
+        qual,
+        tags,
+    })
+}
+
+// Reads SAM records from a text stream, skipping the header.
+pub struct SamReader<R> {
+    reader: R,
+    line: String,
+}
+
+impl<R: BufRead> SamReader<R> {
+    pub fn new(reader: R) -> Self {
+        SamReader {
+            reader,
+            line: String::new(),
+        }
+    }
+
+    pub fn read_record(&mut self) -> Result<Option<SamRecord>> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            let line = self.line.trim_end_matches(['\n', '\r']);
+            if !line.is_empty() && !line.starts_with('@') {
+                return parse_sam_line(line).map(Some);
+            }
+        }
+    }
+}
+
+// Reads records from a decompressed BAM stream.
+pub struct BamReader<R> {
+    reader: R,
+}
+
+impl<R: Read> BamReader<R> {
+    // Skips the header, which must be at the start of the stream.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != b"BAM\x01" {
+            return Err(anyhow!("Input is no BAM file."));
+        }
+        let l_text = read_length(&mut reader)?;
+        std::io::copy(&mut reader.by_ref().take(l_text), &mut std::io::sink())?;
+        for _ in 0..read_length(&mut reader)? {
+            let l_name = read_length(&mut reader)?;
+            // Name and length of each reference sequence.
+            std::io::copy(&mut reader.by_ref().take(l_name + 4), &mut std::io::sink())?;
+        }
+        Ok(BamReader { reader })
+    }
+
+    pub fn read_record(&mut self) -> Result<Option<SamRecord>> {
+        let mut size = [0u8; 4];
+        match self.reader.read_exact(&mut size) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let block_size = i32::from_le_bytes(size);
+        if !(MIN_RECORD_SIZE..=MAX_RECORD_SIZE).contains(&block_size) {
+            return Err(anyhow!("BAM record is malformed"));
+        }
+        let mut block = vec![0u8; block_size as usize];
+        self.reader
+            .read_exact(&mut block)
+            .context("BAM record is truncated")?;
+        decode_bam_record(&block)
+            .map(Some)
+            .ok_or_else(|| anyhow!("BAM record is malformed"))
+    }
+}
+
+fn read_i32(reader: &mut impl Read) -> Result<i32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+// Lengths and counts of the BAM header are signed, but must not be negative.
+fn read_length(reader: &mut impl Read) -> Result<u64> {
+    let length = read_i32(reader)?;
+    u64::try_from(length).map_err(|_| anyhow!("BAM header is malformed"))
+}
+
+// Decodes a BAM record without its block size. Returns None, if the record is shorter than its fields claim.
+fn decode_bam_record(block: &[u8]) -> Option<SamRecord> {
+    let l_read_name = *block.get(8)? as usize;
+    let n_cigar_op = u16::from_le_bytes(block.get(12..14)?.try_into().ok()?) as usize;
+    let flag = u16::from_le_bytes(block.get(14..16)?.try_into().ok()?);
+    let l_seq = i32::from_le_bytes(block.get(16..20)?.try_into().ok()?) as usize;
+
+    let mut pos = 32;
+    let name = block.get(pos..pos + l_read_name.checked_sub(1)?)?;
+    pos += l_read_name + 4 * n_cigar_op;
+    let packed = block.get(pos..pos + l_seq.div_ceil(2))?;
+    let seq = (0..l_seq)
+        .map(|i| {
+            let code = packed[i / 2] >> (4 * (1 - i % 2)) & 0xf;
+            b"=ACMGRSVTWYHKDBN"[code as usize]
+        })
+        .collect();
+    pos += l_seq.div_ceil(2);
+    let raw_qual = block.get(pos..pos + l_seq)?;
+    let qual = if raw_qual.first() == Some(&0xff) {
+        Vec::new()
+    } else {
+        raw_qual.iter().map(|q| q.saturating_add(33)).collect()
+    };
+    pos += l_seq;
+
+    let mut tags = Vec::new();
+    while pos < block.len() {
+        let tag = [*block.get(pos)?, *block.get(pos + 1)?];
+        let kind = *block.get(pos + 2)?;
+        pos += 3;
+        let fixed = |size: usize| block.get(pos..pos + size);
+        let (value, size) = match kind {
+            b'A' => ((*fixed(1)?.first()? as char).to_string(), 1),
+            b'c' => ((fixed(1)?[0] as i8).to_string(), 1),
+            b'C' => (fixed(1)?[0].to_string(), 1),
+            b's' => (
+                i16::from_le_bytes(fixed(2)?.try_into().ok()?).to_string(),
+                2,
+            ),
+            b'S' => (
+                u16::from_le_bytes(fixed(2)?.try_into().ok()?).to_string(),
+                2,
+            ),
+            b'i' => (
+                i32::from_le_bytes(fixed(4)?.try_into().ok()?).to_string(),
+                4,
+            ),
+            b'I' => (
+                u32::from_le_bytes(fixed(4)?.try_into().ok()?).to_string(),
+                4,
+            ),
+            b'f' => (
+                f32::from_le_bytes(fixed(4)?.try_into().ok()?).to_string(),
+                4,
+            ),
+            b'Z' | b'H' => {
+                let end = block[pos..].iter().position(|&b| b == 0)?;
+                (
+                    String::from_utf8_lossy(&block[pos..pos + end]).into_owned(),
+                    end + 1,
+                )
+            }
+            b'B' => {
+                // Arrays are skipped.
+                let width = match *block.get(pos)? {
+                    b'c' | b'C' => 1,
+                    b's' | b'S' => 2,
+                    b'i' | b'I' | b'f' => 4,
+                    _ => return None,
+                };
+                let count = u32::from_le_bytes(block.get(pos + 1..pos + 5)?.try_into().ok()?);
+                pos += 5 + width * count as usize;
+                continue;
+            }
+            _ => return None,
+        };
+        tags.push((tag, value));
+        pos += size;
+    }
+
+    Some(SamRecord {
+        name: String::from_utf8_lossy(name).into_owned(),
+        flag,
+        seq,
+        qual,
+        tags,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_read_group_from_str() {
+        let read_group: ReadGroup = "@RG\\tID:rg1\\tSM:sample1\tPL:ILLUMINA".parse().unwrap();
+        assert_eq!(
+            read_group.header_line(),
+            "@RG\tID:rg1\tSM:sample1\tPL:ILLUMINA"
+        );
+        assert!("SM:sample1".parse::<ReadGroup>().is_err());
+        assert!("ID:rg1\\tsample1".parse::<ReadGroup>().is_err());
+        assert_eq!(
+            ReadGroup::for_sample("read1").header_line(),
+            "@RG\tID:A\tSM:read1"
+        );
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(flag(0, 1), 4);
+        assert_eq!(flag(0, 2), 77);
+        assert_eq!(flag(1, 2), 141);
+    }
+
+    #[test]
+    fn test_header() {
+        let header = header(&ReadGroup::for_sample("s"));
+        assert!(header.starts_with(b"BAM\x01"));
+        let l_text = i32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        let text = std::str::from_utf8(&header[8..8 + l_text]).unwrap();
+        assert!(text.starts_with("@HD\tVN:1.6\tSO:unsorted\n@RG\tID:A\tSM:s\n@PG\tID:umi-transfer"));
+        assert_eq!(&header[8 + l_text..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_encode_record() {
+        let record = Record::with_attrs(
+            "read",
+            Some("1:N:0:GGCC\tRX:Z:AC\tQX:Z:FF\tXi:i:-2"),
+            b"ACGTN",
+            b"+5?IF",
+        );
+        let bam = encode(&record, 77, &ReadGroup::for_sample("s")).unwrap();
+        let block_size = i32::from_le_bytes(bam[..4].try_into().unwrap()) as usize;
+        assert_eq!(block_size, bam.len() - 4);
+        assert_eq!(bam[12], 5); // l_read_name
+        assert_eq!(u16::from_le_bytes([bam[18], bam[19]]), 77);
+        assert_eq!(i32::from_le_bytes(bam[20..24].try_into().unwrap()), 5);
+        assert_eq!(&bam[36..41], b"read\0");
+        assert_eq!(&bam[41..44], &[0x12, 0x48, 0xf0]);
+        assert_eq!(&bam[44..49], &[10, 20, 30, 40, 37]);
+        let mut tags = b"RXZAC\0QXZFF\0Xii".to_vec();
+        tags.extend_from_slice(&(-2i32).to_le_bytes());
+        tags.extend_from_slice(b"RGZA\0");
+        assert_eq!(&bam[49..], &tags[..]);
+    }
+
+    #[test]
+    fn test_encode_fails_on_quality_mismatch() {
+        let record = Record::with_attrs("read", None, b"ACGT", b"FFF");
+        let err = encode(&record, 4, &ReadGroup::for_sample("s")).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Read read has 4 bases, but 3 qualities"));
+    }
+
+    fn sam_record(flag: u16, tags: &str) -> SamRecord {
+        parse_sam_line(&format!(
+            "read\t{flag}\t*\t0\t0\t*\t*\t0\t0\tACGTT\tABCDE{tags}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_into_fastq_selects_mates() {
+        assert!(sam_record(77, "").into_fastq(InputRole::Read1).is_some());
+        assert!(sam_record(77, "").into_fastq(InputRole::Read2).is_none());
+        assert!(sam_record(141, "").into_fastq(InputRole::Read1).is_none());
+        assert!(sam_record(141, "").into_fastq(InputRole::Read2).is_some());
+        assert!(sam_record(4, "").into_fastq(InputRole::Read2).is_some());
+        assert!(sam_record(141, "").into_fastq(InputRole::Other).is_some());
+        assert!(sam_record(0x104, "").into_fastq(InputRole::Other).is_none());
+    }
+
+    #[test]
+    fn test_into_fastq_maps_tags() {
+        let record = sam_record(0x28d, "\tBC:Z:GGCC+TTAA\tOQ:Z:FFFFF\tRX:Z:AC\tQX:Z::F")
+            .into_fastq(InputRole::Read2)
+            .unwrap();
+        assert_eq!(record.id(), "read");
+        assert_eq!(record.desc(), Some("2:Y:0:GGCC+TTAA"));
+        assert_eq!(record.seq(), b"ACGTT");
+        assert_eq!(record.qual(), b"FFFFF");
+
+        let umi = sam_record(77, "\tRX:Z:AC\tQX:Z:FF")
+            .into_fastq(InputRole::Umi)
+            .unwrap();
+        assert_eq!((umi.seq(), umi.qual()), (&b"AC"[..], &b"FF"[..]));
+        assert_eq!(umi.desc(), None);
+
+        // Without RX tag, the UMI is the sequence of the record.
+        let umi = sam_record(4, "").into_fastq(InputRole::Umi).unwrap();
+        assert_eq!(umi.seq(), b"ACGTT");
+    }
+
+    #[test]
+    fn test_into_fastq_restores_reverse_strand() {
+        let record = sam_record(0x14, "").into_fastq(InputRole::Read1).unwrap();
+        assert_eq!(record.seq(), b"AACGT");
+        assert_eq!(record.qual(), b"EDCBA");
+    }
+
+    #[test]
+    fn test_bam_roundtrip() {
+        let record = Record::with_attrs(
+            "read",
+            Some("1:N:0:GGCC\tRX:Z:AC\tXi:i:-2\tXc:A:B"),
+            b"ACGTN",
+            b"+5?IF",
+        );
+        let mut bam = header(&ReadGroup::for_sample("s"));
+        bam.extend(encode(&record, 77, &ReadGroup::for_sample("s")).unwrap());
+
+        let mut reader = BamReader::new(&bam[..]).unwrap();
+        let decoded = reader.read_record().unwrap().unwrap();
+        assert_eq!(decoded.name, "read");
+        assert_eq!(decoded.flag, 77);
+        assert_eq!(decoded.seq, b"ACGTN");
+        assert_eq!(decoded.qual, b"+5?IF");
+        assert_eq!(decoded.tag(b"RX"), Some("AC"));
+        assert_eq!(decoded.tag(b"Xi"), Some("-2"));
+        assert_eq!(decoded.tag(b"Xc"), Some("B"));
+        assert_eq!(decoded.tag(b"RG"), Some("A"));
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_bam_reader_rejects_malformed_sizes() {
+        let mut bam = header(&ReadGroup::for_sample("s"));
+        bam.extend(4i32.to_le_bytes());
+        bam.extend([0u8; 4]);
+        let mut reader = BamReader::new(&bam[..]).unwrap();
+        assert!(reader.read_record().is_err());
+
+        let mut bam = header(&ReadGroup::for_sample("s"));
+        bam.extend(i32::MAX.to_le_bytes());
+        let mut reader = BamReader::new(&bam[..]).unwrap();
+        assert!(reader.read_record().is_err());
+
+        let mut bam = b"BAM\x01".to_vec();
+        bam.extend((-1i32).to_le_bytes());
+        assert!(BamReader::new(&bam[..]).is_err());
+    }
+
+    #[test]
+    fn test_sam_reader_skips_header() {
+        let sam = "@HD\tVN:1.6\n@RG\tID:A\nread\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*\tRX:Z:GG\n";
+        let mut reader = SamReader::new(sam.as_bytes());
+        let record = reader.read_record().unwrap().unwrap();
+        assert_eq!(record.seq, b"ACGT");
+        assert!(record.qual.is_empty());
+        assert_eq!(record.tag(b"RX"), Some("GG"));
+        assert!(reader.read_record().unwrap().is_none());
+        assert!(parse_sam_line("read\t4\t*").is_err());
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(parse_tag("RX:Z:ACGT"), Some(SamTag::String(*b"RX", "ACGT")));
+        assert_eq!(parse_tag("st:A:B"), Some(SamTag::Char(*b"st", b'B')));
+        assert_eq!(parse_tag("1:N:0:GGCC"), None);
+        assert_eq!(parse_tag("XI:i:one"), None);
+    }
+}
@@ -0,0 +1,369 @@
+////////////////////////////////////////////////////////////////
+//  UMIS FROM THE ILLUMINA COMMENT
+////////////////////////////////////////////////////////////////
+
+/*
+Some demultiplexers do not write the UMI to a separate FastQ file, but to the index field of the CASAVA 1.8
+comment instead, e.g. `1:N:0:ACGTACGT+NNNNNNNN`. The index segments are separated by `+`. The chosen segment
+is removed from the comment and embedded into the header like the UMIs of the other subcommands.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use bio::io::fastq::Record;
+use clap::Parser;
+use std::path::PathBuf;
+
+use super::file_io::{self, OutputCompression, OutputFormat};
+use crate::auxiliary::{status, threads_available, threads_per_task};
+use crate::ubam::{InputRole, ReadGroup};
+use crate::umi_errors::RuntimeErrors;
+use crate::umi_external::update_record;
+use crate::umi_header::{
+    build_formatter, casava_index, default_format, HeaderFormat, HeaderPreset, HeaderTemplate,
+};
+
+#[derive(Debug, Parser)]
+pub struct OptsComment {
+    #[clap(
+        short = 'c',
+        long = "correct_numbers",
+        help = "Read numbers will be altered to ensure the canonical read numbers 1 and 2 in output file sequence headers.
+        \n "
+    )]
+    edit_nr: bool,
+    #[clap(flatten)]
+    compression: OutputCompression,
+    #[clap(
+        short = 't',
+        long = "threads",
+        help = "Maximum number of threads to use for processing. Preferably pick odd numbers, 9 or 11 recommended. Defaults to the maximum number of cores available.
+        \n "
+    )]
+    num_threads: Option<usize>,
+    #[clap(
+        short = 'f',
+        long = "force",
+        help = "Overwrite existing output files without further warnings or prompts.
+        \n "
+    )]
+    force: bool,
+    #[clap(
+        short = 'd',
+        long = "delim",
+        help = "Delimiter to use when joining the UMIs to the read name. Defaults to `:`.
+        \n "
+    )]
+    delim: Option<String>,
+    #[clap(
+        long = "header_format",
+        value_enum,
+        help = "Where to write the UMI: `id` appends it to the read ID, `tags` writes it as SAM-style RX:Z tag into the description, e.g. for fgbio, bwa -C or Picard. Defaults to `id`.
+        \n "
+    )]
+    header_format: Option<HeaderFormat>,
+    #[clap(
+        long = "header_preset",
+        alias = "header-preset",
+        value_enum,
+        conflicts_with_all = ["delim", "header_format"],
+        help = "Header layout expected by a downstream tool: `umi_tools` appends the UMI to the read ID with `_`, `bcl_convert` and `illumina` write it as 8th colon-separated field of the read ID, `fgbio` and `picard` write RX:Z tags.
+        \n "
+    )]
+    header_preset: Option<HeaderPreset>,
+    #[clap(
+        long = "header_template",
+        alias = "header-template",
+        conflicts_with_all = ["delim", "header_format", "header_preset"],
+        help = "Custom header layout, e.g. `{id}_{umi} {comment}`. Placeholders are {id}, {comment}, {umi}, {readnr} and {index}. The comment and index no longer contain the UMI segment. The first space separates the read ID from the comment.
+        \n "
+    )]
+    header_template: Option<HeaderTemplate>,
+    #[clap(
+        long = "output_format",
+        value_enum,
+        help = "Write one FastQ file per read (`fastq`), a single FastQ file with the reads of each fragment alternating (`interleaved`), e.g. for bwa mem -p, or a single unaligned BAM file (`bam`) with the reads of each fragment as mates and the UMI as RX and QX tags, e.g. for fgbio or GATK. BAM output is always BGZF-compressed. Defaults to `fastq`.
+        \n "
+    )]
+    output_format: Option<OutputFormat>,
+    #[clap(
+        long = "read_group",
+        help = "Read group of the BAM output, e.g. `ID:rg1\\tSM:sample1\\tPL:ILLUMINA`. All records are assigned to it. Defaults to the ID `A` and a sample named after input file 1.
+        \n "
+    )]
+    read_group: Option<ReadGroup>,
+    #[clap(
+        long = "index_segment",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Segment of the index field that holds the UMI, counted from 1. Segments are separated by `+`, e.g. `2` selects NNNNNNNN of `1:N:0:ACGTACGT+NNNNNNNN`. Defaults to the last segment, if the index has several.
+        \n "
+    )]
+    index_segment: Option<u64>,
+    #[clap(
+        long = "in",
+        required = true,
+        help = "[REQUIRED] Input file 1 with reads.
+    \n "
+    )]
+    r1_in: PathBuf,
+    #[clap(
+        long = "in2",
+        help = "Input file 2 with reads. Omit for single-end data.
+    \n "
+    )]
+    r2_in: Option<PathBuf>,
+    #[clap(
+        long = "out",
+        help = "Path to FastQ output file for R1.
+    \n "
+    )]
+    r1_out: Option<PathBuf>,
+    #[clap(
+        long = "out2",
+        requires = "r2_in",
+        help = "Path to FastQ output file for R2.
+    \n "
+    )]
+    r2_out: Option<PathBuf>,
+}
+
+impl OptsComment {
+    // Whether any of the outputs is written to stdout.
+    pub fn writes_to_stdout(&self) -> bool {
+        [&self.r1_out, &self.r2_out]
+            .iter()
+            .any(|out| out.as_deref().is_some_and(file_io::is_stdio))
+    }
+}
+
+pub fn run(args: OptsComment) -> Result<i32> {
+    let segment = args.index_segment.map(|s| s as usize);
+
+    // Pair each read file with its output path. Single-end data only has a single read file.
+    let mut reads = vec![(args.r1_in.clone(), args.r1_out.clone())];
+    if let Some(r2_in) = &args.r2_in {
+        reads.push((r2_in.clone(), args.r2_out.clone()));
+    }
+
+    // Set the number of threads to max, unless manually specified. In case of failure, use only 1.
+    let num_threads = args.num_threads.unwrap_or_else(threads_available);
+
+    // Determine the number of threads available for output file compression.
+    let output_format = args.output_format.unwrap_or(OutputFormat::Fastq);
+    if args.read_group.is_some() && output_format != OutputFormat::Bam {
+        return Err(anyhow!(RuntimeErrors::ReadGroupWithoutBam));
+    }
+    let tasks = match output_format {
+        OutputFormat::Bam | OutputFormat::Interleaved => 1,
+        OutputFormat::Fastq => reads.len(),
+    };
+    let threads_per_task = threads_per_task(num_threads, tasks);
+
+    let formatter = build_formatter(
+        args.header_template,
+        args.header_preset,
+        default_format(args.header_format, args.delim.as_deref(), output_format),
+        args.delim.as_deref(),
+    );
+
+    // Stdin and stdout may only be used once each.
+    file_io::check_stdio(
+        reads.iter().map(|(input, _)| input),
+        reads.iter().filter_map(|(_, output)| output.as_ref()),
+    )?;
+
+    // Read records from input files
+    let mut inputs = Vec::with_capacity(reads.len());
+    for (i, (input, _)) in reads.iter().enumerate() {
+        let role = if i == 0 {
+            InputRole::Read1
+        } else {
+            InputRole::Read2
+        };
+        let records = file_io::read_records(input, role)
+            .with_context(|| format!("Failed to read records from {}", input.to_string_lossy()))?;
+        inputs.push((input.clone(), records));
+    }
+
+    let mut writers = Vec::with_capacity(reads.len());
+    match output_format {
+        // All reads of a fragment are written to the same BAM file.
+        OutputFormat::Bam => writers.push(file_io::create_bam_output(
+            &reads,
+            &args.compression.gzi,
+            &args.force,
+            &threads_per_task,
+            &args.compression.compression_level,
+            args.read_group.clone(),
+        )?),
+        // The reads of a fragment are written alternately to the same FastQ file.
+        OutputFormat::Interleaved => writers.push(file_io::create_interleaved_output(
+            &reads,
+            &args.compression.gzip,
+            &args.compression.compression_format,
+            &args.compression.gzi,
+            &args.force,
+            &threads_per_task,
+            &args.compression.compression_level,
+        )?),
+        OutputFormat::Fastq => {
+            for (i, (input, output)) in reads.into_iter().enumerate() {
+                // If output paths have been specified, check if the are ok to use or use prefix constructors.
+                let mut output: PathBuf = file_io::resolve_outputpath(output, &input)?;
+
+                // set the correct extension.
+                output = file_io::rectify_extension(
+                    output,
+                    &args.compression.gzip,
+                    &args.compression.compression_format,
+                )?;
+
+                // modify if output path according to compression settings and check if exists.
+                output = file_io::check_outputpath(output, &args.force)?;
+
+                status!(
+                    "Output {} will be saved to: {}",
+                    i + 1,
+                    output.to_string_lossy()
+                );
+
+                writers.push(file_io::create_writer(
+                    output,
+                    &args.compression.gzip,
+                    &args.compression.compression_format,
+                    &args.compression.gzi,
+                    &threads_per_task,
+                    &args.compression.compression_level,
+                    None,
+                )?);
+            }
+        }
+    }
+
+    let outputs = writers.len();
+
+    // Record counter
+    let mut counter: i32 = 0;
+
+    status!("Moving UMIs from the comments to the headers...");
+
+    // Iterate over records in input files, which must all contain the same number of records.
+    for records in file_io::LockstepRecords::new(inputs) {
+        let records = records?;
+
+        // Step counter
+        counter += 1;
+
+        if records.iter().any(|rec| rec.id() != records[0].id()) {
+            return Err(anyhow!(RuntimeErrors::ReadPairMismatch));
+        }
+
+        for (i, rec) in records.into_iter().enumerate() {
+            // There is either one writer per read or a single BAM or interleaved writer.
+            let writer = &mut writers[i % outputs];
+            let (rec, umi) = take_index_segment(rec, segment)?;
+
+            // Write to Output file
+            let read_nr = if args.edit_nr {
+                Some(i as u8 + 1)
+            } else {
+                None
+            };
+            // The index reads' qualities are not part of the comment.
+            let rec = update_record(rec, umi.as_bytes(), b"", &*formatter, read_nr)?;
+            writer.write_record(rec)?;
+        }
+    }
+    // Complete the compressed streams, so that errors while writing them are reported.
+    for writer in writers {
+        writer.finish()?;
+    }
+    status!("Processed {:?} records", counter);
+    Ok(counter)
+}
+
+// Removes an index segment from the CASAVA 1.8 comment of a record and returns it separately.
+// Without a chosen segment, the last one is taken, provided that there are several.
+fn take_index_segment(input: Record, segment: Option<usize>) -> Result<(Record, String)> {
+    let not_found = || {
+        anyhow!(RuntimeErrors::IndexSegmentNotFound(
+            input.id().to_string(),
+            segment
+        ))
+    };
+    let desc = input.desc().ok_or_else(not_found)?;
+    let index = casava_index(desc).ok_or_else(not_found)?;
+
+    let mut segments: Vec<&str> = index.split('+').collect();
+    let pos = match segment {
+        Some(s) => s - 1,
+        // A lone segment is the sample barcode rather than a UMI.
+        None if segments.len() < 2 && !index.is_empty() => {
+            return Err(anyhow!(RuntimeErrors::SingleIndexSegment(
+                input.id().to_string()
+            )))
+        }
+        None => segments.len() - 1,
+    };
+    if pos >= segments.len() || segments[pos].is_empty() {
+        return Err(not_found());
+    }
+    let umi = segments.remove(pos).to_string();
+
+    // The index is followed by the rest of the comment, if any.
+    let start = index.as_ptr() as usize - desc.as_ptr() as usize;
+    let new_desc = [
+        &desc[..start],
+        &segments.join("+"),
+        &desc[start + index.len()..],
+    ]
+    .concat();
+
+    let trimmed = Record::with_attrs(input.id(), Some(&new_desc), input.seq(), input.qual());
+    Ok((trimmed, umi))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn record(desc: Option<&str>) -> Record {
+        Record::with_attrs("read1", desc, b"ACGT", b"FFFF")
+    }
+
+    #[test]
+    fn test_take_last_index_segment() {
+        let (rec, umi) = take_index_segment(record(Some("1:N:0:ACGTACGT+TTGGCCAA")), None).unwrap();
+        assert_eq!(umi, "TTGGCCAA");
+        assert_eq!(rec.desc(), Some("1:N:0:ACGTACGT"));
+        assert_eq!(rec.seq(), b"ACGT");
+    }
+
+    #[test]
+    fn test_take_chosen_index_segment_keeps_further_fields() {
+        let (rec, umi) = take_index_segment(
+            record(Some("2:N:0:ACGTACGT+TTGGCCAA+GGGG\tBC:Z:X")),
+            Some(2),
+        )
+        .unwrap();
+        assert_eq!(umi, "TTGGCCAA");
+        assert_eq!(rec.desc(), Some("2:N:0:ACGTACGT+GGGG\tBC:Z:X"));
+    }
+
+    #[test]
+    fn test_take_index_segment_fails_without_segment() {
+        assert!(take_index_segment(record(Some("1:N:0:ACGTACGT")), Some(2)).is_err());
+        assert!(take_index_segment(record(Some("1:N:0:")), None).is_err());
+        assert!(take_index_segment(record(Some("no comment")), None).is_err());
+        let err = take_index_segment(record(None), None).unwrap_err();
+        assert!(err.to_string().contains("Read read1"));
+    }
+
+    #[test]
+    fn test_take_index_segment_fails_for_single_index() {
+        let err = take_index_segment(record(Some("1:N:0:ACGTACGT")), None).unwrap_err();
+        assert!(err.to_string().contains("--index_segment"));
+        let (_, umi) = take_index_segment(record(Some("1:N:0:ACGTACGT")), Some(1)).unwrap();
+        assert_eq!(umi, "ACGTACGT");
+    }
+}
@@ -1,17 +1,54 @@
 use std::path::PathBuf;
 
+use crate::file_io::InputCompression;
+
 #[derive(Debug)]
 pub enum RuntimeErrors {
+    BamMateCount(usize),
+    DualUmiInHeader(String),
+    DuplicateUmiID(String),
     FileExists(Option<PathBuf>),
     FileNotFound(Option<PathBuf>),
+    GziUnavailable,
+    IndexSegmentNotFound(String, Option<usize>),
+    MultipleStdio(&'static str),
+    OutputCountMismatch(usize, usize),
+    OutputPathRequired,
     OutputNotWriteable(Option<PathBuf>),
     ReadIDMismatch,
+    QualityLengthMismatch(String, usize, usize),
+    ReadGroupWithoutBam,
+    ReadNumberNotFound(String),
+    ReadPairMismatch,
+    ReadStructureMismatch(String, usize, String),
     ReadWriteError(bio::io::fastq::Record),
+    SingleIndexSegment(String),
+    SingleOutput(&'static str),
+    TruncatedInput(PathBuf, usize),
+    UmiIDMismatch,
+    UmiNotInHeader(String),
+    UmiNotFound(String),
+    UnsupportedCompression(PathBuf, InputCompression),
 }
 
 impl std::fmt::Display for RuntimeErrors {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Self::BamMateCount(mates) => write!(
+                f,
+                "BAM output supports single-end or paired reads, but {} read files were given.",
+                mates
+            ),
+            Self::DualUmiInHeader(id) => write!(
+                f,
+                "Read {} carries a dual UMI joined by `-` or `+`, which can't be restored as a single UMI read.",
+                id
+            ),
+            Self::DuplicateUmiID(id) => write!(
+                f,
+                "The UMI file contains several records with the ID {}. Each read must have a unique UMI record.",
+                id
+            ),
             Self::FileExists(None) => {
                 write!(f, "Output file exists, but must not be overwritten.")
             }
@@ -26,6 +63,34 @@ impl std::fmt::Display for RuntimeErrors {
             Self::FileNotFound(Some(path)) => {
                 write!(f, "{} does not exist or is not readable!", path.display())
             }
+            Self::GziUnavailable => write!(
+                f,
+                "A .gzi index can only be written alongside BGZF-compressed FastQ output files. Please add --gzip and --compression_format bgzf, specify output paths other than `-` and omit --output_format bam."
+            ),
+            Self::IndexSegmentNotFound(id, None) => write!(
+                f,
+                "Read {} carries no index in a CASAVA 1.8 comment (1:N:0:...).",
+                id
+            ),
+            Self::IndexSegmentNotFound(id, Some(segment)) => write!(
+                f,
+                "Read {} carries no index segment {} in a CASAVA 1.8 comment (1:N:0:...).",
+                id, segment
+            ),
+            Self::MultipleStdio(kind) => write!(
+                f,
+                "Only one {} may be streamed via `-`. Please specify file paths for the others.",
+                kind
+            ),
+            Self::OutputCountMismatch(outputs, inputs) => write!(
+                f,
+                "{} output files were specified with --out, but only {} input files with --in.",
+                outputs, inputs
+            ),
+            Self::OutputPathRequired => write!(
+                f,
+                "Output paths can't be derived for input from stdin. Please specify them explicitly."
+            ),
             Self::OutputNotWriteable(None) => {
                 write!(f, "Output file is missing or not writeable.")
             }
@@ -36,11 +101,66 @@ impl std::fmt::Display for RuntimeErrors {
             ),
             Self::ReadIDMismatch => write!(
                 f,
-                "IDs of UMI and read records mismatch. Please provide sorted files as input!"
+                "IDs of UMI and read records mismatch. Please provide sorted files as input or use --unordered_umi!"
+            ),
+            Self::QualityLengthMismatch(id, bases, qualities) => write!(
+                f,
+                "Read {} has {} bases, but {} qualities. BAM output requires one quality per base.",
+                id, bases, qualities
+            ),
+            Self::ReadGroupWithoutBam => write!(
+                f,
+                "A read group can only be assigned to BAM output. Please add --output_format bam or omit --read_group."
+            ),
+            Self::ReadNumberNotFound(id) => write!(
+                f,
+                "Read {} carries its read number neither in a CASAVA 1.8 comment (1:N:0:...) nor as /1 or /2 suffix. Please omit --correct_numbers!",
+                id
+            ),
+            Self::ReadPairMismatch => write!(
+                f,
+                "IDs of read 1 and read 2 records mismatch. Please provide sorted files as input!"
+            ),
+            Self::ReadStructureMismatch(id, length, structure) => write!(
+                f,
+                "Read {} with a length of {} bases does not fit the read structure {}.",
+                id, length, structure
             ),
             Self::ReadWriteError(record) => {
                 write!(f, "Failure to write read {} to file.", record.id())
             }
+            Self::SingleIndexSegment(id) => write!(
+                f,
+                "Read {} carries a single index segment, which is usually the sample barcode. Please choose the segment holding the UMI with --index_segment.",
+                id
+            ),
+            Self::SingleOutput(kind) => write!(
+                f,
+                "{} output is written to a single file. Please specify only one output path.",
+                kind
+            ),
+            Self::TruncatedInput(path, count) => write!(
+                f,
+                "{} ended after {} records, but the other input files contain more records. Please check for truncated files!",
+                path.display(),
+                count
+            ),
+            Self::UmiIDMismatch => write!(
+                f,
+                "IDs of the records in both UMI files mismatch. Please provide both UMI files in the same order!"
+            ),
+            Self::UmiNotInHeader(id) => write!(
+                f,
+                "No UMI found in the header of read {}. Please check --delim, --header_format, --header_preset or --header_template!",
+                id
+            ),
+            Self::UmiNotFound(id) => write!(f, "No UMI record found for read {}.", id),
+            Self::UnsupportedCompression(path, compression) => write!(
+                f,
+                "{} is compressed with {:?}, which is not supported. Please decompress it first or use gzip or zstd.",
+                path.display(),
+                compression
+            ),
         }
     }
 }
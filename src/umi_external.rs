@@ -1,34 +1,44 @@
 use anyhow::{anyhow, Context, Result};
+use bio::{alphabets::dna::revcomp, io::fastq::Record};
 use clap::Parser;
-use itertools::izip;
 use std::path::PathBuf;
 
-use super::file_io;
-use crate::auxiliary::{threads_available, threads_per_task};
+use super::file_io::{self, OutputCompression, OutputFormat};
+use crate::auxiliary::{status, threads_available, threads_per_task};
+use crate::read_id::{normalise, IdMatcher, IdRule};
+use crate::read_structure::ReadStructure;
+use crate::ubam::{InputRole, ReadGroup};
 use crate::umi_errors::RuntimeErrors;
+use crate::umi_header::{
+    build_formatter, correct_read_nr, default_format, HeaderFields, HeaderFormat, HeaderFormatter,
+    HeaderPreset, HeaderTemplate,
+};
+use crate::umi_index::UmiIndex;
+
+// Largest --memory_limit in MiB, whose size in bytes still fits into a usize.
+const MAX_MEMORY_LIMIT: u64 = (usize::MAX >> 20) as u64;
+
 #[derive(Debug, Parser)]
+#[clap(group(
+    clap::ArgGroup::new("mate2")
+        .args(["r2_in", "interleaved_in"])
+))]
+#[clap(group(
+    clap::ArgGroup::new("umi_range")
+        .args(["umi_start", "umi_length", "umi_structure"])
+        .multiple(true)
+))]
 pub struct OptsExternal {
     #[clap(
         short = 'c',
         long = "correct_numbers",
         help = "Read numbers will be altered to ensure the canonical read numbers 1 and 2 in output file sequence headers.
+        Index reads given as further --in files keep their read numbers.
         \n "
     )]
     edit_nr: bool,
-    #[clap(
-        short = 'z',
-        long = "gzip",
-        help = "Compress output files. Turned off by default.
-        \n "
-    )]
-    gzip: bool,
-    #[clap(
-        short = 'l',
-        long = "compression_level",
-        help = "Choose the compression level: Maximum 9, defaults to 3. Higher numbers result in smaller files but take longer to compress.
-        \n "
-    )]
-    compression_level: Option<u32>,
+    #[clap(flatten)]
+    compression: OutputCompression,
     #[clap(
         short = 't',
         long = "threads",
@@ -57,20 +67,63 @@ pub struct OptsExternal {
         \n "
     )]
     delim: Option<String>,
+    #[clap(
+        long = "header_format",
+        value_enum,
+        help = "Where to write the UMI: `id` appends it to the read ID, `tags` writes it as SAM-style RX:Z and QX:Z tags into the description, e.g. for fgbio, bwa -C or Picard. Defaults to `id`.
+        \n "
+    )]
+    header_format: Option<HeaderFormat>,
+    #[clap(
+        long = "header_preset",
+        alias = "header-preset",
+        value_enum,
+        conflicts_with_all = ["delim", "header_format"],
+        help = "Header layout expected by a downstream tool: `umi_tools` appends the UMI to the read ID with `_`, `bcl_convert` and `illumina` write it as 8th colon-separated field of the read ID, `fgbio` and `picard` write RX:Z and QX:Z tags.
+        \n "
+    )]
+    header_preset: Option<HeaderPreset>,
+    #[clap(
+        long = "header_template",
+        alias = "header-template",
+        conflicts_with_all = ["delim", "header_format", "header_preset"],
+        help = "Custom header layout, e.g. `{id}_{umi} {comment}` or `{id} {readnr}:N:0:{index}+{umi}`. Placeholders are {id}, {comment}, {umi}, {umi_qual}, {readnr} and {index}, the latter two are parsed from the Illumina comment. The first space separates the read ID from the comment.
+        \n "
+    )]
+    header_template: Option<HeaderTemplate>,
+    #[clap(
+        long = "output_format",
+        value_enum,
+        help = "Write one FastQ file per read (`fastq`), a single FastQ file with the reads of each fragment alternating (`interleaved`), e.g. for bwa mem -p, or a single unaligned BAM file (`bam`) with the reads of each fragment as mates and the UMI as RX and QX tags, e.g. for fgbio or GATK. BAM output is always BGZF-compressed. Defaults to `fastq`.
+        \n "
+    )]
+    output_format: Option<OutputFormat>,
+    #[clap(
+        long = "read_group",
+        help = "Read group of the BAM output, e.g. `ID:rg1\\tSM:sample1\\tPL:ILLUMINA`. All records are assigned to it. Defaults to the ID `A` and a sample named after input file 1.
+        \n "
+    )]
+    read_group: Option<ReadGroup>,
     #[clap(
         long = "in",
+        value_name = "R1_IN",
         required = true,
-        help = "[REQUIRED] Input file 1 with reads.
+        help = "[REQUIRED] Input file 1 with reads. Repeat --in for further read files, e.g. index reads, which all receive the UMI.
     \n "
     )]
-    r1_in: PathBuf,
+    reads_in: Vec<PathBuf>,
     #[clap(
         long = "in2",
-        required = true,
-        help = "[REQUIRED] Input file 2 with reads.
+        help = "Input file 2 with reads. Omit for single-end data.
+    \n "
+    )]
+    r2_in: Option<PathBuf>,
+    #[clap(
+        long = "interleaved_in",
+        help = "Input file 1 contains both reads of each fragment alternately, e.g. as written by bwa or fastp with --interleaved. Both reads must match the UMI record.
     \n "
     )]
-    r2_in: PathBuf,
+    interleaved_in: bool,
     #[clap(
         short = 'u',
         long = "umi",
@@ -79,20 +132,124 @@ pub struct OptsExternal {
         \n"
     )]
     ru_in: PathBuf,
+    #[clap(
+        long = "umi2",
+        help = "Second input file with UMI, e.g. for kits with UMIs in both index reads. Both UMIs are joined with the --umi_separator.
+        \n "
+    )]
+    ru2_in: Option<PathBuf>,
+    #[clap(
+        long = "umi_separator",
+        requires = "ru2_in",
+        help = "Separator to use when joining the UMIs of --umi and --umi2. Defaults to `-`.
+        \n "
+    )]
+    umi_separator: Option<String>,
+    #[clap(
+        long = "duplex",
+        requires = "ru2_in",
+        help = "Treat --umi and --umi2 as the two halves of a duplex UMI and join them in lexicographical order, so that reads of both strands of a molecule share the same UMI. With the tags header format, the strand is recorded as `st:Z:A` or `st:Z:B` next to the RX tag.
+        \n "
+    )]
+    duplex: bool,
+    #[clap(
+        long = "reverse_complement",
+        help = "Reverse-complement the UMI sequences and reverse their quality strings, e.g. if the UMI was read from the antisense strand.
+        \n "
+    )]
+    reverse_complement: bool,
+    #[clap(
+        long = "revcomp_prefix",
+        requires = "reverse_complement",
+        help = "Prefix reverse-complemented UMIs with `r`, following the convention of bcl-convert.
+        \n "
+    )]
+    revcomp_prefix: bool,
+    #[clap(
+        long = "umi_structure",
+        help = "Read structure of the UMI files, e.g. `8M2S+T` to use the first 8 bases as UMI (M) and discard the rest. Defaults to the whole read.
+        \n "
+    )]
+    umi_structure: Option<ReadStructure>,
+    #[clap(
+        long = "umi_start",
+        conflicts_with = "umi_structure",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Position of the first UMI base in the UMI read, counting from 1. Defaults to 1.
+        \n "
+    )]
+    umi_start: Option<u64>,
+    #[clap(
+        long = "umi_length",
+        conflicts_with = "umi_structure",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Number of UMI bases to use from --umi_start onwards. Defaults to the rest of the UMI read.
+        \n "
+    )]
+    umi_length: Option<u64>,
+    #[clap(
+        long = "short_umi",
+        value_enum,
+        requires = "umi_range",
+        help = "How to handle UMI reads that are too short for the --umi_start and --umi_length or --umi_structure: `error` aborts, `pad` fills the missing bases with N and `skip` omits all reads of that fragment from the output. Defaults to `error`.
+        \n "
+    )]
+    short_umi: Option<ShortUmiPolicy>,
+    #[clap(
+        long = "id_rules",
+        value_enum,
+        value_delimiter = ',',
+        help = "Comma-separated rules to normalise read IDs, if they don't match exactly. Applied cumulatively in the given order: `mate_suffix` strips /1, /2 or SRA-style .1, .2 suffixes, `sra_prefix` strips SRA accession prefixes and `coordinates` compares only the Illumina lane, tile and coordinates.
+        \n "
+    )]
+    id_rules: Vec<IdRule>,
+    #[clap(
+        long = "unordered_umi",
+        help = "The UMI file is not sorted in the same order as the read files. UMI records will be indexed by their IDs instead, which requires more memory.
+        \n "
+    )]
+    unordered_umi: bool,
+    #[clap(
+        long = "memory_limit",
+        requires = "unordered_umi",
+        value_parser = clap::value_parser!(u64).range(..=MAX_MEMORY_LIMIT),
+        help = "Memory in MiB to use for indexing UMI records with --unordered_umi. Further UMI records are spilled to disk and the corresponding reads are written last. Defaults to 1024.
+        \n "
+    )]
+    memory_limit: Option<u64>,
+    #[clap(
+        long = "tmp_dir",
+        requires = "unordered_umi",
+        help = "Directory for UMI records spilled to disk. Defaults to the system's temporary directory.
+        \n "
+    )]
+    tmp_dir: Option<PathBuf>,
     #[clap(
         long = "out",
-        help = "Path to FastQ output file for R1.
+        value_name = "R1_OUT",
+        help = "Path to FastQ output file for R1. Repeat --out to specify the outputs of further read files given with --in in the same order.
     \n "
     )]
-    r1_out: Option<PathBuf>,
+    reads_out: Vec<PathBuf>,
     #[clap(
         long = "out2",
+        requires = "mate2",
         help = "Path to FastQ output file for R2.
     \n "
     )]
     r2_out: Option<PathBuf>,
 }
 
+impl OptsExternal {
+    // Whether any of the outputs is written to stdout.
+    pub fn writes_to_stdout(&self) -> bool {
+        self.reads_out
+            .iter()
+            .chain(&self.r2_out)
+            .any(|out| file_io::is_stdio(out))
+    }
+}
+
 pub fn run(args: OptsExternal) -> Result<i32> {
     // Enables editing id in output file 2 if --edit-nr flag was included
     let mut edit_nr = false;
@@ -100,130 +257,533 @@ pub fn run(args: OptsExternal) -> Result<i32> {
         edit_nr = true;
     }
 
+    // Orientation of the UMI sequences
+    let orientation = match (args.reverse_complement, args.revcomp_prefix) {
+        (false, _) => UmiOrientation::Forward,
+        (true, false) => UmiOrientation::ReverseComplement,
+        (true, true) => UmiOrientation::ReverseComplementPrefixed,
+    };
+
+    // Select the UMI bases by a read structure or by start and length.
+    let umi_structure = match (&args.umi_structure, args.umi_start, args.umi_length) {
+        (Some(structure), _, _) => Some(structure.clone()),
+        (None, None, None) => None,
+        (None, start, length) => Some(ReadStructure::umi_range(
+            start.unwrap_or(1) as usize,
+            length.map(|l| l as usize),
+        )),
+    };
+    let short_umi = args.short_umi.unwrap_or(ShortUmiPolicy::Error);
+    let output_format = args.output_format.unwrap_or(OutputFormat::Fastq);
+    if args.read_group.is_some() && output_format != OutputFormat::Bam {
+        return Err(anyhow!(RuntimeErrors::ReadGroupWithoutBam));
+    }
+    let formatter = build_formatter(
+        args.header_template,
+        args.header_preset,
+        default_format(args.header_format, args.delim.as_deref(), output_format),
+        args.delim.as_deref(),
+    );
+
+    // Pair each read file with its output path. Single-end data only has a single read file.
+    if args.reads_out.len() > args.reads_in.len() {
+        return Err(anyhow!(RuntimeErrors::OutputCountMismatch(
+            args.reads_out.len(),
+            args.reads_in.len()
+        )));
+    }
+    let mut reads: Vec<(PathBuf, Option<PathBuf>)> = args
+        .reads_in
+        .iter()
+        .enumerate()
+        .map(|(i, input)| (input.clone(), args.reads_out.get(i).cloned()))
+        .collect();
+    // --in2 always denotes the second read file, any further --in follow after it.
+    // An interleaved input file 1 provides the second reads as well.
+    if let Some(r2_in) = &args.r2_in {
+        reads.insert(1, (r2_in.clone(), args.r2_out.clone()));
+    } else if args.interleaved_in {
+        reads.insert(1, (args.reads_in[0].clone(), args.r2_out.clone()));
+    }
+    // Whether the read file at an index is the second mate of an interleaved input file 1.
+    let is_interleaved_mate = |i: usize| args.interleaved_in && i == 1;
+
     // Set the number of threads to max, unless manually specified. In case of failure, use only 1.
     let num_threads = args.num_threads.unwrap_or_else(threads_available);
 
     // Determine the number of threads available for output file compression.
-    let threads_per_task = threads_per_task(num_threads, 2);
-
-    // Read FastQ records from input files
-    let r1 = file_io::read_fastq(&args.r1_in)
-        .with_context(|| {
-            format!(
-                "Failed to read records from {}",
-                &args.r1_in.to_string_lossy()
-            )
-        })?
-        .records();
-    let r2 = file_io::read_fastq(&args.r2_in)
-        .with_context(|| {
-            format!(
-                "Failed to read records from {}",
-                &args.r2_in.to_string_lossy()
-            )
-        })?
-        .records();
-    let ru = file_io::read_fastq(&args.ru_in)
-        .with_context(|| {
-            format!(
-                "Failed to read records from {}",
-                &args.ru_in.to_string_lossy()
-            )
-        })?
-        .records();
-
-    // If output paths have been specified, check if the are ok to use or use prefix constructors.
-    let mut output1: PathBuf = args
-        .r1_out
-        .unwrap_or(file_io::append_umi_to_path(&args.r1_in));
-    let mut output2: PathBuf = args
-        .r2_out
-        .unwrap_or(file_io::append_umi_to_path(&args.r2_in));
-
-    // set the correct extension.
-    output1 = file_io::rectify_extension(output1, &args.gzip)?;
-    output2 = file_io::rectify_extension(output2, &args.gzip)?;
-
-    // modify if output path according to compression settings and check if exists.
-    output1 = file_io::check_outputpath(output1, &args.force)?;
-    output2 = file_io::check_outputpath(output2, &args.force)?;
-
-    println!("Output 1 will be saved to: {}", output1.to_string_lossy());
-    println!("Output 2 will be saved to: {}", output2.to_string_lossy());
-
-    let mut write_output_r1 = file_io::create_writer(
-        output1,
-        &args.gzip,
-        &threads_per_task,
-        &args.compression_level,
-        None,
-    )?;
-    let mut write_output_r2 = file_io::create_writer(
-        output2,
-        &args.gzip,
-        &threads_per_task,
-        &args.compression_level,
-        None,
+    let tasks = match output_format {
+        OutputFormat::Bam | OutputFormat::Interleaved => 1,
+        OutputFormat::Fastq => reads.len(),
+    };
+    let threads_per_task = threads_per_task(num_threads, tasks);
+
+    // One or two UMI files, whose UMIs are joined.
+    let umi_files: Vec<&PathBuf> = [&args.ru_in].into_iter().chain(&args.ru2_in).collect();
+    let joiner = UmiJoiner {
+        structure: umi_structure,
+        short_umi,
+        orientation,
+        separator: args.umi_separator.as_deref().unwrap_or("-"),
+        duplex: args.duplex,
+        rules: &args.id_rules,
+    };
+
+    // Stdin and stdout may only be used once each.
+    file_io::check_stdio(
+        reads
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !is_interleaved_mate(*i))
+            .map(|(_, (input, _))| input)
+            .chain(umi_files.iter().copied()),
+        reads.iter().filter_map(|(_, output)| output.as_ref()),
     )?;
 
+    // Read records from input files. For SAM and BAM inputs, the role selects the mate and the UMI tags.
+    let roles = reads
+        .iter()
+        .enumerate()
+        .map(|(i, _)| match i {
+            0 if args.interleaved_in => InputRole::Other,
+            0 => InputRole::Read1,
+            1 if args.r2_in.is_some() => InputRole::Read2,
+            _ => InputRole::Other,
+        })
+        .chain(umi_files.iter().map(|_| InputRole::Umi));
+    let mut inputs = Vec::with_capacity(reads.len() + umi_files.len());
+    for (i, (input, role)) in reads
+        .iter()
+        .map(|(input, _)| input)
+        .chain(umi_files.iter().copied())
+        .zip(roles)
+        .enumerate()
+    {
+        if is_interleaved_mate(i) {
+            continue;
+        }
+        let records = file_io::read_records(input, role)
+            .with_context(|| format!("Failed to read records from {}", input.to_string_lossy()))?;
+        if i == 0 && args.interleaved_in {
+            // Both mates are iterated separately, but read from the same stream.
+            let (read1, read2) = file_io::deinterleave(records);
+            inputs.push((input.clone(), read1));
+            inputs.push((input.clone(), read2));
+        } else {
+            inputs.push((input.clone(), records));
+        }
+    }
+    let umi_inputs = inputs.split_off(reads.len());
+
+    let mut writers = Vec::with_capacity(reads.len());
+    match output_format {
+        // All reads of a fragment are written to the same BAM file.
+        OutputFormat::Bam => writers.push(file_io::create_bam_output(
+            &reads,
+            &args.compression.gzi,
+            &args.force,
+            &threads_per_task,
+            &args.compression.compression_level,
+            args.read_group.clone(),
+        )?),
+        // The reads of a fragment are written alternately to the same FastQ file.
+        OutputFormat::Interleaved => writers.push(file_io::create_interleaved_output(
+            &reads,
+            &args.compression.gzip,
+            &args.compression.compression_format,
+            &args.compression.gzi,
+            &args.force,
+            &threads_per_task,
+            &args.compression.compression_level,
+        )?),
+        OutputFormat::Fastq => {
+            for (i, (input, output)) in reads.into_iter().enumerate() {
+                // If output paths have been specified, check if the are ok to use or use prefix constructors.
+                // Both reads of an interleaved input file are named after it, hence they are told apart by a suffix.
+                let mut output: PathBuf = match i {
+                    0 | 1 if args.interleaved_in => file_io::resolve_outputpath_with_suffix(
+                        output,
+                        &input,
+                        ["_R1_with_UMIs", "_R2_with_UMIs"][i],
+                    )?,
+                    _ => file_io::resolve_outputpath(output, &input)?,
+                };
+
+                // set the correct extension.
+                output = file_io::rectify_extension(
+                    output,
+                    &args.compression.gzip,
+                    &args.compression.compression_format,
+                )?;
+
+                // modify if output path according to compression settings and check if exists.
+                output = file_io::check_outputpath(output, &args.force)?;
+
+                status!(
+                    "Output {} will be saved to: {}",
+                    i + 1,
+                    output.to_string_lossy()
+                );
+
+                writers.push(file_io::create_writer(
+                    output,
+                    &args.compression.gzip,
+                    &args.compression.compression_format,
+                    &args.compression.gzi,
+                    &threads_per_task,
+                    &args.compression.compression_level,
+                    None,
+                )?);
+            }
+        }
+    }
+
+    let outputs = writers.len();
+
     // Record counter
     let mut counter: i32 = 0;
+    let mut skipped: usize = 0;
 
-    println!("Transferring UMIs to records...");
+    // Compares the read IDs, optionally after normalising them.
+    let mut id_matcher = IdMatcher::new(args.id_rules.clone());
 
-    // Iterate over records in input files
-    for (r1_rec_res, ru_rec_res, r2_rec_res) in izip!(r1, ru, r2) {
-        let r1_rec = r1_rec_res?;
-        let r2_rec = r2_rec_res?;
-        let ru_rec = ru_rec_res?;
+    // Embeds the UMI into all reads of a fragment and writes them to the output files.
+    let mut transfer = |reads: Vec<Record>, ru_rec: &Record| -> Result<()> {
+        // The IDs are checked for skipped fragments as well, so that out-of-sync inputs are always detected.
+        if reads
+            .iter()
+            .any(|rec| !id_matcher.matches(rec.id(), ru_rec.id()))
+        {
+            return Err(anyhow!(RuntimeErrors::ReadIDMismatch));
+        }
+        if joiner.is_skipped(ru_rec) {
+            skipped += 1;
+            return Ok(());
+        }
 
         // Step counter
         counter += 1;
 
-        if r1_rec.id().eq(ru_rec.id()) {
+        for (i, rec) in reads.into_iter().enumerate() {
+            // There is either one writer per read or a single BAM or interleaved writer.
+            let writer = &mut writers[i % outputs];
             // Write to Output file
-            let read_nr = if edit_nr { Some(1) } else { None };
-            let r1_rec = update_record(r1_rec, ru_rec.seq(), args.delim.as_ref(), read_nr)?;
+            // Only the two mates carry canonical read numbers, further index reads are left unchanged.
+            let read_nr = match i {
+                0 | 1 if edit_nr => Some(i as u8 + 1),
+                _ => None,
+            };
+            let mut rec = update_record(rec, ru_rec.seq(), ru_rec.qual(), &*formatter, read_nr)?;
 
-            write_output_r1.write_record(r1_rec)?;
-        } else {
-            return Err(anyhow!(RuntimeErrors::ReadIDMismatch));
+            // The strand tag of duplex UMIs is carried in the description of the joined UMI record.
+            // It is only written alongside other SAM tags, since it doesn't belong into an Illumina comment.
+            if args.duplex && formatter.writes_tags() {
+                rec = append_tag(rec, ru_rec.desc().unwrap_or_default());
+            }
+
+            writer.write_record(rec)?;
         }
+        Ok(())
+    };
 
-        if r2_rec.id().eq(ru_rec.id()) {
-            // Write to Output file
-            let read_nr = if edit_nr { Some(2) } else { None };
-            let r2_rec = update_record(r2_rec, ru_rec.seq(), args.delim.as_ref(), read_nr)?;
+    if args.unordered_umi {
+        // Index the UMI records by their ID and look up the reads of each fragment.
+        status!("Indexing UMI records...");
+        let memory_limit = (args.memory_limit.unwrap_or(1024) << 20) as usize;
+        let umis = file_io::LockstepRecords::new(umi_inputs).map(|umis| joiner.join(umis?));
+        let mut index = UmiIndex::build(
+            umis,
+            inputs.len(),
+            memory_limit,
+            args.tmp_dir.as_deref(),
+            &args.id_rules,
+        )?;
+        if index.is_spilled() {
+            status!("UMI records exceed the memory limit and are partially spilled to disk.");
+        }
 
-            write_output_r2.write_record(r2_rec)?;
-        } else {
-            return Err(anyhow!(RuntimeErrors::ReadIDMismatch));
+        status!("Transferring UMIs to records...");
+
+        // Iterate over records in read files, which must all contain the same number of records.
+        for records in file_io::LockstepRecords::new(inputs) {
+            if let Some((reads, ru_rec)) = index.lookup_or_defer(records?)? {
+                transfer(reads, ru_rec)?;
+            }
+        }
+
+        let deferred = index.resolve_deferred(&mut transfer)?;
+        if deferred > 0 {
+            status!("Transferred UMIs to {} records spilled to disk", deferred);
         }
+    } else {
+        status!("Transferring UMIs to records...");
+
+        // Iterate over records in input files, which must all contain the same number of records.
+        let num_reads = inputs.len();
+        inputs.extend(umi_inputs);
+        for records in file_io::LockstepRecords::new(inputs) {
+            let mut reads = records?;
+            let umis = reads.split_off(num_reads);
+            let ru_rec = joiner.join(umis)?;
+            transfer(reads, &ru_rec)?;
+        }
+    }
+    if skipped > 0 {
+        status!(
+            "Skipped {} records with UMI reads too short for the UMI range",
+            skipped
+        );
+    }
+    for report in id_matcher.report() {
+        status!("{}", report);
     }
-    println!("Processed {:?} records", counter);
+    // Complete the compressed streams, so that errors while writing them are reported.
+    for writer in writers {
+        writer.finish()?;
+    }
+    status!("Processed {:?} records", counter);
     Ok(counter)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "snake_case")]
+enum ShortUmiPolicy {
+    Error,
+    Pad,
+    Skip,
+}
+
+// Settings to combine the UMI records of a fragment into a single record.
+struct UmiJoiner<'a> {
+    structure: Option<ReadStructure>,
+    short_umi: ShortUmiPolicy,
+    orientation: UmiOrientation,
+    separator: &'a str,
+    duplex: bool,
+    rules: &'a [IdRule],
+}
+
+impl UmiJoiner<'_> {
+    // Combines the UMI records of a fragment into a single record, restricted to the molecular barcode segments
+    // if a read structure was given. Sequences are joined with the separator, qualities with as many spaces as for SAM
+    // QX tags, so that both keep the same length.
+    // Skipped fragments are denoted by a record without sequence, so that they can be indexed as well.
+    fn join(&self, mut umis: Vec<Record>) -> Result<Record> {
+        if umis.len() == 1
+            && self.structure.is_none()
+            && self.orientation == UmiOrientation::Forward
+        {
+            return Ok(umis.pop().unwrap());
+        }
+
+        let id = normalise(umis[0].id(), self.rules);
+        if umis.iter().any(|umi| normalise(umi.id(), self.rules) != id) {
+            return Err(anyhow!(RuntimeErrors::UmiIDMismatch));
+        }
+
+        let mut halves: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(umis.len());
+        for umi in &umis {
+            let (seq, qual) = match &self.structure {
+                Some(structure) => {
+                    let extracted = match self.short_umi {
+                        _ if structure.fits(umi.seq().len()) => structure.extract(umi)?,
+                        ShortUmiPolicy::Pad if umi.seq().len() < structure.fixed_length() => {
+                            structure.extract(&structure.pad(umi))?
+                        }
+                        ShortUmiPolicy::Skip => {
+                            return Ok(Record::with_attrs(umis[0].id(), None, b"", b""))
+                        }
+                        _ => structure.extract(umi)?,
+                    };
+                    (extracted.umi_seq, extracted.umi_qual)
+                }
+                None => (umi.seq().to_vec(), umi.qual().to_vec()),
+            };
+            halves.push(self.orientation.apply(seq, qual));
+        }
+
+        // Duplex UMIs are written in canonical order, reads of the B strand carry the halves swapped.
+        let desc = if self.duplex {
+            let strand = if halves[0].0 <= halves[1].0 { 'A' } else { 'B' };
+            halves.sort_by(|a, b| a.0.cmp(&b.0));
+            Some(format!("st:Z:{strand}"))
+        } else {
+            umis[0].desc().map(str::to_string)
+        };
+
+        let (seqs, quals): (Vec<Vec<u8>>, Vec<Vec<u8>>) = halves.into_iter().unzip();
+        let gap = b" ".repeat(self.separator.len());
+        Ok(Record::with_attrs(
+            umis[0].id(),
+            desc.as_deref(),
+            &seqs.join(self.separator.as_bytes()),
+            &quals.join(&gap[..]),
+        ))
+    }
+
+    fn is_skipped(&self, umi: &Record) -> bool {
+        self.short_umi == ShortUmiPolicy::Skip && umi.seq().is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UmiOrientation {
+    Forward,
+    ReverseComplement,
+    // bcl-convert marks reverse-complemented UMIs with a leading `r`.
+    ReverseComplementPrefixed,
+}
+
+impl UmiOrientation {
+    fn apply(&self, seq: Vec<u8>, mut qual: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+        match self {
+            UmiOrientation::Forward => (seq, qual),
+            UmiOrientation::ReverseComplement => {
+                qual.reverse();
+                (revcomp(seq), qual)
+            }
+            UmiOrientation::ReverseComplementPrefixed => {
+                qual.reverse();
+                ([b"r".as_slice(), &revcomp(seq)].concat(), qual)
+            }
+        }
+    }
+}
+
+// Appends a SAM tag to the description of a record.
+fn append_tag(input: Record, tag: &str) -> Record {
+    let desc = match input.desc() {
+        Some(desc) => format!("{desc}\t{tag}"),
+        None => tag.to_string(),
+    };
+    Record::with_attrs(input.id(), Some(&desc), input.seq(), input.qual())
+}
+
 // Updates the header and description of the reads accordingly
-fn update_record(
+pub fn update_record(
     input: bio::io::fastq::Record,
     umi: &[u8],
-    umi_sep: Option<&String>,
+    umi_qual: &[u8],
+    formatter: &dyn HeaderFormatter,
     edit_nr: Option<u8>,
 ) -> Result<bio::io::fastq::Record> {
-    let delim = umi_sep.as_ref().map(|s| s.as_str()).unwrap_or(":"); // the delimiter for the UMI
-    if let Some(number) = edit_nr {
-        let new_id = &[input.id(), delim, std::str::from_utf8(umi).unwrap()].concat();
-        let mut new_desc = String::from(input.desc().unwrap());
-        new_desc.replace_range(0..1, &number.to_string());
-        let desc: Option<&str> = Some(&new_desc);
-        let new_record =
-            bio::io::fastq::Record::with_attrs(new_id, desc, input.seq(), input.qual());
-        Ok(new_record)
-    } else {
-        let new_id = &[input.id(), delim, std::str::from_utf8(umi).unwrap()].concat();
-        let new_record =
-            bio::io::fastq::Record::with_attrs(new_id, input.desc(), input.seq(), input.qual());
-        Ok(new_record)
+    // Correct the read number, before the header is formatted.
+    let (id, desc) = match edit_nr {
+        Some(number) => correct_read_nr(input.id(), input.desc(), number)?,
+        None => (input.id().to_string(), input.desc().map(str::to_string)),
+    };
+    let (new_id, new_desc) = formatter.format(&HeaderFields {
+        id: &id,
+        desc: desc.as_deref(),
+        umi: std::str::from_utf8(umi).unwrap(),
+        umi_qual: std::str::from_utf8(umi_qual).unwrap(),
+        read_nr: edit_nr,
+    });
+    let new_record =
+        bio::io::fastq::Record::with_attrs(&new_id, new_desc.as_deref(), input.seq(), input.qual());
+    Ok(new_record)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::umi_header::SamTags;
+
+    #[test]
+    fn test_orientation_reverse_complements_umi() {
+        let (seq, qual) =
+            UmiOrientation::ReverseComplement.apply(b"AACGN".to_vec(), b"ABCDE".to_vec());
+        assert_eq!(seq, b"NCGTT");
+        assert_eq!(qual, b"EDCBA");
+
+        let (seq, qual) =
+            UmiOrientation::ReverseComplementPrefixed.apply(b"AACG".to_vec(), b"ABCD".to_vec());
+        assert_eq!(seq, b"rCGTT");
+        assert_eq!(qual, b"DCBA");
+    }
+
+    #[test]
+    fn test_update_record_writes_sam_tags() {
+        let record = Record::with_attrs("read", Some("1:N:0:ACGT"), b"TTTT", b"FFFF");
+        let updated = update_record(record, b"AACC", b"ABCD", &SamTags, None).unwrap();
+        assert_eq!(updated.id(), "read");
+        assert_eq!(updated.desc(), Some("1:N:0:ACGT\tRX:Z:AACC\tQX:Z:ABCD"));
+
+        let record = Record::with_attrs("read", None, b"TTTT", b"FFFF");
+        let updated = update_record(record, b"AACC", b"ABCD", &SamTags, None).unwrap();
+        assert_eq!(updated.desc(), Some("RX:Z:AACC\tQX:Z:ABCD"));
+    }
+
+    fn joiner(structure: Option<&str>, short_umi: ShortUmiPolicy) -> UmiJoiner<'static> {
+        UmiJoiner {
+            structure: structure.map(|s| s.parse().unwrap()),
+            short_umi,
+            orientation: UmiOrientation::Forward,
+            separator: "-",
+            duplex: false,
+            rules: &[],
+        }
+    }
+
+    #[test]
+    fn test_join_umis_reverse_complements_each_half() {
+        let umis = vec![
+            Record::with_attrs("read", None, b"AAC", b"ABC"),
+            Record::with_attrs("read", None, b"GGT", b"DEF"),
+        ];
+        let joiner = UmiJoiner {
+            orientation: UmiOrientation::ReverseComplement,
+            ..joiner(None, ShortUmiPolicy::Error)
+        };
+        let joined = joiner.join(umis).unwrap();
+        assert_eq!(joined.seq(), b"GTT-ACC");
+        assert_eq!(joined.qual(), b"CBA FED");
+    }
+
+    #[test]
+    fn test_join_umis_pads_qualities_to_separator_length() {
+        let umis = vec![
+            Record::with_attrs("read", None, b"AAC", b"ABC"),
+            Record::with_attrs("read", None, b"GGT", b"DEF"),
+        ];
+        let joiner = UmiJoiner {
+            separator: "__",
+            ..joiner(None, ShortUmiPolicy::Error)
+        };
+        let joined = joiner.join(umis).unwrap();
+        assert_eq!(joined.seq(), b"AAC__GGT");
+        assert_eq!(joined.qual(), b"ABC  DEF");
+    }
+
+    #[test]
+    fn test_join_umis_short_umi_policies() {
+        let umi = || vec![Record::with_attrs("read", None, b"ACGTA", b"FFFFF")];
+
+        assert!(joiner(Some("2S6M+S"), ShortUmiPolicy::Error)
+            .join(umi())
+            .is_err());
+
+        let padded = joiner(Some("2S6M+S"), ShortUmiPolicy::Pad)
+            .join(umi())
+            .unwrap();
+        assert_eq!(padded.seq(), b"GTANNN");
+        assert_eq!(padded.qual(), b"FFF!!!");
+
+        let skipper = joiner(Some("2S6M+S"), ShortUmiPolicy::Skip);
+        let skipped = skipper.join(umi()).unwrap();
+        assert!(skipper.is_skipped(&skipped));
+        assert_eq!(skipped.id(), "read");
+
+        let fitting = skipper
+            .join(vec![Record::with_attrs(
+                "read",
+                None,
+                b"ACGTACGT",
+                b"FFFFFFFF",
+            )])
+            .unwrap();
+        assert!(!skipper.is_skipped(&fitting));
+        assert_eq!(fitting.seq(), b"GTACGT");
     }
 }
@@ -0,0 +1,724 @@
+////////////////////////////////////////////////////////////////
+//  HEADER FORMATTING
+////////////////////////////////////////////////////////////////
+
+/*
+Downstream tools expect the UMI at different positions of the FastQ header: umi_tools looks for it after the
+last `_` of the read ID, bcl-convert and other Illumina software write it as 8th colon-separated field of the
+read ID, while fgbio and Picard can carry SAM-style tags in the comment into BAM files. Each layout is
+implemented as a header formatter, which is chosen once per run by a preset or the individual options.
+User-defined header templates are compiled once per run as well and merely filled in for each record.
+Formatters can also parse their own layout, which allows to move UMIs out of the headers again.
+*/
+
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+use crate::file_io::OutputFormat;
+use crate::umi_errors::RuntimeErrors;
+
+// Parts of a record header that are available to header formatters.
+pub struct HeaderFields<'a> {
+    pub id: &'a str,
+    pub desc: Option<&'a str>,
+    pub umi: &'a str,
+    pub umi_qual: &'a str,
+    // Read number to write, if it was corrected. Otherwise it is taken from the comment.
+    pub read_nr: Option<u8>,
+}
+
+// Header of a record, from which the UMI has been removed again.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParsedHeader {
+    pub id: String,
+    pub desc: Option<String>,
+    pub umi: String,
+    // Only layouts with the UMI qualities preserve them.
+    pub umi_qual: Option<String>,
+}
+
+pub trait HeaderFormatter {
+    // Returns the new ID and description of a record.
+    fn format(&self, fields: &HeaderFields) -> (String, Option<String>);
+
+    // Inverse of `format`: Splits a header into the original ID and description and the UMI, if it has this layout.
+    fn parse(&self, id: &str, desc: Option<&str>) -> Option<ParsedHeader>;
+
+    // Whether the description holds tab-separated SAM tags, to which further tags can be appended.
+    fn writes_tags(&self) -> bool {
+        false
+    }
+}
+
+// Writes the UMI as 8th colon-separated field of Illumina read IDs, replacing any previous 8th field.
+pub struct IlluminaField;
+
+impl HeaderFormatter for IlluminaField {
+    fn format(&self, fields: &HeaderFields) -> (String, Option<String>) {
+        let base = match fields.id.match_indices(':').nth(6) {
+            Some((pos, _)) => &fields.id[..pos],
+            None => fields.id,
+        };
+        let id = [base, ":", fields.umi].concat();
+        (id, fields.desc.map(str::to_string))
+    }
+
+    fn parse(&self, id: &str, desc: Option<&str>) -> Option<ParsedHeader> {
+        let (pos, _) = id.match_indices(':').nth(6)?;
+        Some(ParsedHeader {
+            id: id[..pos].to_string(),
+            desc: desc.map(str::to_string),
+            umi: id[pos + 1..].to_string(),
+            umi_qual: None,
+        })
+    }
+}
+
+// Writes the UMI sequence and qualities as RX and QX tags after the comment. Tags are separated by tabs as in SAM files.
+pub struct SamTags;
+
+impl HeaderFormatter for SamTags {
+    fn format(&self, fields: &HeaderFields) -> (String, Option<String>) {
+        // UMIs without qualities, e.g. from the index field of the comment, only get an RX tag.
+        let tags = match fields.umi_qual {
+            "" => format!("RX:Z:{}", fields.umi),
+            qual => format!("RX:Z:{}\tQX:Z:{}", fields.umi, qual),
+        };
+        let desc = match fields.desc {
+            Some(desc) => format!("{desc}\t{tags}"),
+            None => tags,
+        };
+        (fields.id.to_string(), Some(desc))
+    }
+
+    fn parse(&self, id: &str, desc: Option<&str>) -> Option<ParsedHeader> {
+        let (mut umi, mut umi_qual) = (None, None);
+        let mut rest: Vec<&str> = Vec::new();
+        for field in desc?.split('\t') {
+            match (field.strip_prefix("RX:Z:"), field.strip_prefix("QX:Z:")) {
+                (Some(rx), _) => umi = Some(rx.to_string()),
+                (_, Some(qx)) => umi_qual = Some(qx.to_string()),
+                _ => rest.push(field),
+            }
+        }
+        Some(ParsedHeader {
+            id: id.to_string(),
+            desc: Some(rest.join("\t")).filter(|desc| !desc.is_empty()),
+            umi: umi?,
+            umi_qual,
+        })
+    }
+
+    fn writes_tags(&self) -> bool {
+        true
+    }
+}
+
+// Placeholders of header templates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Id,
+    Comment,
+    Umi,
+    UmiQual,
+    ReadNr,
+    Index,
+}
+
+impl Placeholder {
+    const ALL: [(&'static str, Placeholder); 6] = [
+        ("id", Placeholder::Id),
+        ("comment", Placeholder::Comment),
+        ("umi", Placeholder::Umi),
+        ("umi_qual", Placeholder::UmiQual),
+        ("readnr", Placeholder::ReadNr),
+        ("index", Placeholder::Index),
+    ];
+
+    fn render(&self, fields: &HeaderFields, out: &mut String) {
+        match self {
+            Placeholder::Id => out.push_str(fields.id),
+            Placeholder::Comment => out.push_str(fields.desc.unwrap_or_default()),
+            Placeholder::Umi => out.push_str(fields.umi),
+            Placeholder::UmiQual => out.push_str(fields.umi_qual),
+            Placeholder::ReadNr => match fields.read_nr {
+                Some(number) => out.push_str(&number.to_string()),
+                None => out.push_str(fields.desc.and_then(casava_read_nr).unwrap_or_default()),
+            },
+            Placeholder::Index => {
+                out.push_str(fields.desc.and_then(casava_index).unwrap_or_default())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Field(Placeholder),
+}
+
+// A header template such as `{id}_{umi} {comment}`. The first literal space separates the ID from the description.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderTemplate {
+    id: Vec<TemplatePart>,
+    desc: Option<Vec<TemplatePart>>,
+}
+
+impl HeaderTemplate {
+    // Template appending the UMI to the read ID, equivalent to `{id}<delim>{umi} {comment}`.
+    pub fn id_suffix(delim: &str) -> Self {
+        HeaderTemplate {
+            id: vec![
+                TemplatePart::Field(Placeholder::Id),
+                TemplatePart::Literal(delim.to_string()),
+                TemplatePart::Field(Placeholder::Umi),
+            ],
+            desc: Some(vec![TemplatePart::Field(Placeholder::Comment)]),
+        }
+    }
+
+    // Matches text against template parts and collects the values of all placeholders. Placeholders are
+    // separated by the literals between them: UMIs and other fields end at the first occurrence of the next
+    // literal, read IDs and comments at the last one that still allows a match.
+    fn capture<'t>(
+        parts: &[TemplatePart],
+        text: &'t str,
+        captures: &mut Vec<(Placeholder, &'t str)>,
+    ) -> bool {
+        match parts {
+            [] => text.is_empty(),
+            [TemplatePart::Literal(literal), rest @ ..] => text
+                .strip_prefix(literal.as_str())
+                .is_some_and(|text| HeaderTemplate::capture(rest, text, captures)),
+            [TemplatePart::Field(placeholder), rest @ ..] => {
+                let mut ends: Vec<usize> = match rest.first() {
+                    None => vec![text.len()],
+                    Some(TemplatePart::Literal(literal)) => text
+                        .match_indices(literal.as_str())
+                        .map(|(i, _)| i)
+                        .collect(),
+                    Some(TemplatePart::Field(_)) => (0..=text.len())
+                        .filter(|&i| text.is_char_boundary(i))
+                        .collect(),
+                };
+                if matches!(placeholder, Placeholder::Id | Placeholder::Comment) {
+                    ends.reverse();
+                }
+                for end in ends {
+                    captures.push((*placeholder, &text[..end]));
+                    if HeaderTemplate::capture(rest, &text[end..], captures) {
+                        return true;
+                    }
+                    captures.pop();
+                }
+                false
+            }
+        }
+    }
+
+    fn render(parts: &[TemplatePart], fields: &HeaderFields) -> String {
+        let mut out = String::new();
+        for part in parts {
+            match part {
+                TemplatePart::Literal(text) => out.push_str(text),
+                TemplatePart::Field(placeholder) => placeholder.render(fields, &mut out),
+            }
+        }
+        out
+    }
+}
+
+impl HeaderFormatter for HeaderTemplate {
+    fn format(&self, fields: &HeaderFields) -> (String, Option<String>) {
+        let id = HeaderTemplate::render(&self.id, fields);
+        let desc = self
+            .desc
+            .as_ref()
+            .map(|parts| HeaderTemplate::render(parts, fields))
+            .filter(|desc| !desc.is_empty());
+        (id, desc)
+    }
+
+    // Only the placeholders {id}, {comment}, {umi} and {umi_qual} are restored, all others are dropped.
+    fn parse(&self, id: &str, desc: Option<&str>) -> Option<ParsedHeader> {
+        let mut captures = Vec::new();
+        if !HeaderTemplate::capture(&self.id, id, &mut captures) {
+            return None;
+        }
+        let desc_matches = match &self.desc {
+            Some(parts) => HeaderTemplate::capture(parts, desc.unwrap_or_default(), &mut captures),
+            None => desc.is_none(),
+        };
+        if !desc_matches {
+            return None;
+        }
+
+        let find = |placeholder: Placeholder| {
+            captures
+                .iter()
+                .find(|(p, _)| *p == placeholder)
+                .map(|(_, value)| value.to_string())
+        };
+        Some(ParsedHeader {
+            id: find(Placeholder::Id)?,
+            desc: find(Placeholder::Comment).filter(|desc| !desc.is_empty()),
+            umi: find(Placeholder::Umi)?,
+            umi_qual: find(Placeholder::UmiQual),
+        })
+    }
+}
+
+impl FromStr for HeaderTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<TemplatePart> = Vec::new();
+        let mut rest = s;
+
+        while !rest.is_empty() {
+            match rest.find(['{', '}']) {
+                Some(0) if rest.starts_with("{{") || rest.starts_with("}}") => {
+                    parts.push(TemplatePart::Literal(rest[..1].to_string()));
+                    rest = &rest[2..];
+                }
+                Some(0) if rest.starts_with('{') => {
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("Unclosed placeholder in header template {s}."))?;
+                    let name = &rest[1..end];
+                    let placeholder = Placeholder::ALL
+                        .iter()
+                        .find(|(n, _)| *n == name)
+                        .map(|(_, p)| *p)
+                        .ok_or_else(|| {
+                            format!(
+                                "Unknown placeholder {{{name}}} in header template {s}. Use {}.",
+                                Placeholder::ALL.map(|(n, _)| format!("{{{n}}}")).join(", ")
+                            )
+                        })?;
+                    parts.push(TemplatePart::Field(placeholder));
+                    rest = &rest[end + 1..];
+                }
+                Some(0) => return Err(format!("Unmatched `}}` in header template {s}.")),
+                Some(pos) => {
+                    parts.push(TemplatePart::Literal(rest[..pos].to_string()));
+                    rest = &rest[pos..];
+                }
+                None => {
+                    parts.push(TemplatePart::Literal(rest.to_string()));
+                    rest = "";
+                }
+            }
+        }
+
+        // Split the parts at the first literal space into ID and description.
+        let split = parts.iter().enumerate().find_map(|(i, part)| match part {
+            TemplatePart::Literal(text) => text.find(' ').map(|pos| (i, pos)),
+            TemplatePart::Field(_) => None,
+        });
+        let (id, desc) = match split {
+            Some((i, pos)) => {
+                let mut desc = parts.split_off(i + 1);
+                let TemplatePart::Literal(text) = parts.pop().unwrap() else {
+                    unreachable!()
+                };
+                let (before, after) = (&text[..pos], text[pos + 1..].trim_start_matches(' '));
+                if !before.is_empty() {
+                    parts.push(TemplatePart::Literal(before.to_string()));
+                }
+                if !after.is_empty() {
+                    desc.insert(0, TemplatePart::Literal(after.to_string()));
+                }
+                (parts, Some(desc))
+            }
+            None => (parts, None),
+        };
+
+        if id.is_empty() {
+            return Err(format!(
+                "Header template '{s}' must contain a read ID before the first space."
+            ));
+        }
+        Ok(HeaderTemplate { id, desc })
+    }
+}
+
+// Read number of a CASAVA 1.8 comment, e.g. `1` of `1:N:0:ACGT`.
+pub fn casava_read_nr(desc: &str) -> Option<&str> {
+    let (read_nr, _) = desc.split_once(':')?;
+    (!read_nr.is_empty() && read_nr.bytes().all(|b| b.is_ascii_digit())).then_some(read_nr)
+}
+
+// Sets the read number of a record to `number`, both in the CASAVA 1.8 comment (`1:N:0:ACGT`) and a `/1` style
+// suffix of the read ID. Fails, if the record carries its read number in neither of both places.
+pub fn correct_read_nr(
+    id: &str,
+    desc: Option<&str>,
+    number: u8,
+) -> Result<(String, Option<String>)> {
+    let mut found = false;
+
+    let new_id = match id.rsplit_once('/') {
+        Some((stem, suffix))
+            if !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            found = true;
+            format!("{stem}/{number}")
+        }
+        _ => id.to_string(),
+    };
+
+    let new_desc = desc.map(|desc| match casava_read_nr(desc) {
+        Some(read_nr) => {
+            found = true;
+            format!("{number}{}", &desc[read_nr.len()..])
+        }
+        None => desc.to_string(),
+    });
+
+    if !found {
+        return Err(anyhow!(RuntimeErrors::ReadNumberNotFound(id.to_string())));
+    }
+    Ok((new_id, new_desc))
+}
+
+// Index of a CASAVA 1.8 comment, e.g. `ACGT+TTGA` of `1:N:0:ACGT+TTGA`.
+pub fn casava_index(desc: &str) -> Option<&str> {
+    casava_read_nr(desc)?;
+    let index = desc.splitn(4, ':').nth(3)?;
+    // Any further fields of the comment, e.g. SAM tags, are not part of the index.
+    index.split(char::is_whitespace).next()
+}
+
+// UMIs consist of A, C, G, T and N. Dual UMIs may be joined by `-` or `+` and bcl-convert prefixes reverse
+// complemented UMIs with `r`.
+pub fn is_nucleotide_umi(umi: &str) -> bool {
+    !umi.is_empty()
+        && umi.split(['-', '+']).all(|part| {
+            let part = part.strip_prefix('r').unwrap_or(part);
+            !part.is_empty()
+                && part
+                    .bytes()
+                    .all(|b| matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum HeaderFormat {
+    // Append the UMI to the read ID.
+    Id,
+    // Write the UMI as SAM-style RX and QX tags into the description, after the Illumina comment.
+    Tags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum HeaderPreset {
+    UmiTools,
+    BclConvert,
+    Illumina,
+    Fgbio,
+    Picard,
+}
+
+// BAM output carries the UMI as RX and QX tags, unless a delimiter or header format places it elsewhere.
+pub fn default_format(
+    format: Option<HeaderFormat>,
+    delim: Option<&str>,
+    output: OutputFormat,
+) -> Option<HeaderFormat> {
+    match (format, delim, output) {
+        (None, None, OutputFormat::Bam) => Some(HeaderFormat::Tags),
+        _ => format,
+    }
+}
+
+// Chooses the header formatter for a run. A template or preset takes precedence over the individual options.
+pub fn build_formatter(
+    template: Option<HeaderTemplate>,
+    preset: Option<HeaderPreset>,
+    format: Option<HeaderFormat>,
+    delim: Option<&str>,
+) -> Box<dyn HeaderFormatter> {
+    if let Some(template) = template {
+        return Box::new(template);
+    }
+    match preset {
+        Some(HeaderPreset::UmiTools) => Box::new(HeaderTemplate::id_suffix("_")),
+        Some(HeaderPreset::BclConvert | HeaderPreset::Illumina) => Box::new(IlluminaField),
+        Some(HeaderPreset::Fgbio | HeaderPreset::Picard) => Box::new(SamTags),
+        None => match format.unwrap_or(HeaderFormat::Id) {
+            HeaderFormat::Id => Box::new(HeaderTemplate::id_suffix(delim.unwrap_or(":"))),
+            HeaderFormat::Tags => Box::new(SamTags),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const ID: &str = "SCILIFELAB:500:NGISTLM:1:1101:19994:1016";
+
+    fn format_with(preset: HeaderPreset) -> (String, Option<String>) {
+        build_formatter(None, Some(preset), None, None).format(&HeaderFields {
+            id: ID,
+            desc: Some("1:N:0:GCTTCAGGGT"),
+            umi: "ACGT",
+            umi_qual: "FF:F",
+            read_nr: None,
+        })
+    }
+
+    #[test]
+    fn test_preset_umi_tools() {
+        let (id, desc) = format_with(HeaderPreset::UmiTools);
+        assert_eq!(id, format!("{ID}_ACGT"));
+        assert_eq!(desc.as_deref(), Some("1:N:0:GCTTCAGGGT"));
+    }
+
+    #[test]
+    fn test_preset_bcl_convert() {
+        let (id, desc) = format_with(HeaderPreset::BclConvert);
+        assert_eq!(id, format!("{ID}:ACGT"));
+        assert_eq!(desc.as_deref(), Some("1:N:0:GCTTCAGGGT"));
+    }
+
+    #[test]
+    fn test_preset_illumina_replaces_existing_umi_field() {
+        let (id, _) = IlluminaField.format(&HeaderFields {
+            id: &format!("{ID}:TTTT"),
+            desc: None,
+            umi: "ACGT",
+            umi_qual: "FFFF",
+            read_nr: None,
+        });
+        assert_eq!(id, format!("{ID}:ACGT"));
+        assert_eq!(format_with(HeaderPreset::Illumina).0, format!("{ID}:ACGT"));
+    }
+
+    #[test]
+    fn test_preset_fgbio() {
+        let (id, desc) = format_with(HeaderPreset::Fgbio);
+        assert_eq!(id, ID);
+        assert_eq!(
+            desc.as_deref(),
+            Some("1:N:0:GCTTCAGGGT\tRX:Z:ACGT\tQX:Z:FF:F")
+        );
+    }
+
+    #[test]
+    fn test_preset_picard() {
+        assert_eq!(
+            format_with(HeaderPreset::Picard),
+            format_with(HeaderPreset::Fgbio)
+        );
+    }
+
+    fn fields<'a>(desc: Option<&'a str>, read_nr: Option<u8>) -> HeaderFields<'a> {
+        HeaderFields {
+            id: "read",
+            desc,
+            umi: "ACGT",
+            umi_qual: "FFFF",
+            read_nr,
+        }
+    }
+
+    #[test]
+    fn test_template_renders_placeholders() {
+        let template: HeaderTemplate = "{id}_{umi} {comment}".parse().unwrap();
+        let (id, desc) = template.format(&fields(Some("1:N:0:GGCC"), None));
+        assert_eq!(id, "read_ACGT");
+        assert_eq!(desc.as_deref(), Some("1:N:0:GGCC"));
+
+        let template: HeaderTemplate = "{id} {readnr}:N:0:{index}+{umi} QX:{umi_qual}"
+            .parse()
+            .unwrap();
+        let (id, desc) = template.format(&fields(Some("2:N:0:GGCC"), None));
+        assert_eq!(id, "read");
+        assert_eq!(desc.as_deref(), Some("2:N:0:GGCC+ACGT QX:FFFF"));
+
+        // Corrected read numbers take precedence over the comment.
+        let (_, desc) = template.format(&fields(Some("3:N:0:GGCC"), Some(2)));
+        assert_eq!(desc.as_deref(), Some("2:N:0:GGCC+ACGT QX:FFFF"));
+    }
+
+    #[test]
+    fn test_template_without_comment() {
+        let template: HeaderTemplate = "{id}:{umi} {comment}".parse().unwrap();
+        assert_eq!(
+            template.format(&fields(None, None)),
+            ("read:ACGT".to_string(), None)
+        );
+
+        let template: HeaderTemplate = "{{{id}}}".parse().unwrap();
+        assert_eq!(
+            template.format(&fields(None, None)),
+            ("{read}".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn test_template_rejects_invalid_placeholders() {
+        for text in ["{id}_{barcode}", "{id}_{umi", "{id}}", " {id}", ""] {
+            assert!(text.parse::<HeaderTemplate>().is_err(), "{text} was parsed");
+        }
+    }
+
+    #[test]
+    fn test_id_suffix_equals_parsed_template() {
+        assert_eq!(
+            HeaderTemplate::id_suffix("_"),
+            "{id}_{umi} {comment}".parse().unwrap()
+        );
+    }
+
+    fn parse_with(formatter: &dyn HeaderFormatter, id: &str, desc: Option<&str>) -> ParsedHeader {
+        formatter.parse(id, desc).unwrap()
+    }
+
+    #[test]
+    fn test_parse_inverts_format() {
+        let fields = HeaderFields {
+            id: ID,
+            desc: Some("1:N:0:GCTTCAGGGT"),
+            umi: "ACGT",
+            umi_qual: "FF:F",
+            read_nr: None,
+        };
+        for (formatter, umi_qual) in [
+            (build_formatter(None, None, None, None), None),
+            (build_formatter(None, None, None, Some("_")), None),
+            (
+                build_formatter(None, Some(HeaderPreset::Illumina), None, None),
+                None,
+            ),
+            (
+                build_formatter(None, Some(HeaderPreset::Fgbio), None, None),
+                Some("FF:F"),
+            ),
+            (
+                build_formatter(
+                    Some("{id}_{umi}_{umi_qual} {comment}".parse().unwrap()),
+                    None,
+                    None,
+                    None,
+                ),
+                Some("FF:F"),
+            ),
+        ] {
+            let (id, desc) = formatter.format(&fields);
+            let parsed = parse_with(&*formatter, &id, desc.as_deref());
+            assert_eq!(parsed.id, ID);
+            assert_eq!(parsed.desc.as_deref(), Some("1:N:0:GCTTCAGGGT"));
+            assert_eq!(parsed.umi, "ACGT");
+            assert_eq!(parsed.umi_qual.as_deref(), umi_qual);
+        }
+    }
+
+    #[test]
+    fn test_parse_without_comment() {
+        let parsed = parse_with(&HeaderTemplate::id_suffix(":"), "read:1:ACGT", None);
+        assert_eq!((parsed.id.as_str(), parsed.desc), ("read:1", None));
+        assert_eq!(parsed.umi, "ACGT");
+
+        let parsed = parse_with(&SamTags, "read", Some("RX:Z:ACGT"));
+        assert_eq!((parsed.id.as_str(), parsed.desc), ("read", None));
+    }
+
+    #[test]
+    fn test_parse_fails_on_other_layouts() {
+        assert!(HeaderTemplate::id_suffix("_")
+            .parse("read:ACGT", None)
+            .is_none());
+        assert!(IlluminaField.parse("read:ACGT", None).is_none());
+        assert!(SamTags.parse("read", Some("1:N:0:ACGT")).is_none());
+        assert!(SamTags.parse("read", None).is_none());
+        let template: HeaderTemplate = "{id}".parse().unwrap();
+        assert!(template.parse("read", None).is_none());
+    }
+
+    #[test]
+    fn test_correct_read_nr() {
+        let corrected = |id, desc| correct_read_nr(id, desc, 2).ok();
+        assert_eq!(
+            corrected("read", Some("3:N:0:ACGT")),
+            Some(("read".to_string(), Some("2:N:0:ACGT".to_string())))
+        );
+        assert_eq!(
+            corrected("read", Some("13:N:0:ACGT")),
+            Some(("read".to_string(), Some("2:N:0:ACGT".to_string())))
+        );
+        assert_eq!(
+            corrected("read/3", None),
+            Some(("read/2".to_string(), None))
+        );
+        assert_eq!(
+            corrected("read#0/3", Some("length=100")),
+            Some(("read#0/2".to_string(), Some("length=100".to_string())))
+        );
+        assert_eq!(
+            corrected("read/1", Some("1:N:0:ACGT")),
+            Some(("read/2".to_string(), Some("2:N:0:ACGT".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_correct_read_nr_fails_without_read_number() {
+        for (id, desc) in [
+            ("read", None),
+            ("read", Some("length=100")),
+            ("a/b", Some("N:0")),
+        ] {
+            let err = correct_read_nr(id, desc, 1).unwrap_err();
+            assert!(err.to_string().contains(id), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_casava_fields() {
+        assert_eq!(casava_read_nr("1:N:0:ACGT+TTGA"), Some("1"));
+        assert_eq!(casava_index("1:N:0:ACGT+TTGA"), Some("ACGT+TTGA"));
+        assert_eq!(casava_index("1:N:0:ACGT\tRX:Z:AAA"), Some("ACGT"));
+        assert_eq!(casava_read_nr("N:0:ACGT"), None);
+        assert_eq!(casava_index("st:Z:A"), None);
+    }
+
+    #[test]
+    fn test_formatter_from_individual_options() {
+        let fields = HeaderFields {
+            id: "read",
+            desc: None,
+            umi: "ACGT",
+            umi_qual: "FFFF",
+            read_nr: None,
+        };
+        let (id, desc) = build_formatter(None, None, None, None).format(&fields);
+        assert_eq!((id.as_str(), desc), ("read:ACGT", None));
+
+        let (id, _) =
+            build_formatter(None, None, Some(HeaderFormat::Id), Some("-")).format(&fields);
+        assert_eq!(id, "read-ACGT");
+        assert!(!build_formatter(None, None, None, None).writes_tags());
+
+        let formatter = build_formatter(None, None, Some(HeaderFormat::Tags), Some("-"));
+        assert_eq!(formatter.format(&fields).0, "read");
+        assert!(formatter.writes_tags());
+    }
+
+    #[test]
+    fn test_is_nucleotide_umi() {
+        assert!(is_nucleotide_umi("ACGTN"));
+        assert!(is_nucleotide_umi("acgt"));
+        assert!(is_nucleotide_umi("ACGT-TTGG"));
+        assert!(is_nucleotide_umi("rACGT+rTTGG"));
+        assert!(!is_nucleotide_umi("1016"));
+        assert!(!is_nucleotide_umi("ACGT-"));
+        assert!(!is_nucleotide_umi("r"));
+        assert!(!is_nucleotide_umi(""));
+    }
+}
@@ -0,0 +1,456 @@
+use anyhow::{anyhow, Context, Result};
+use bio::io::fastq::{Record, Writer as FastqWriter};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::File,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+
+use super::file_io;
+use crate::read_id::{normalise, IdRule};
+use crate::umi_errors::RuntimeErrors;
+
+////////////////////////////////////////////////////////////////
+//  UMI INDEX FOR UNORDERED INPUT
+////////////////////////////////////////////////////////////////
+
+/*
+Hash join of reads and UMI records, for UMI files that are not sorted in the same order as the reads.
+
+UMI records are held in memory until the memory limit is reached. All further UMI records are spilled to
+a fixed number of partition files on disk, chosen by the hash of the record ID. Reads, whose UMI is not found
+in memory, are deferred to the matching partition as well. Once all reads have been processed, the partitions
+are loaded into memory one by one to resolve the deferred reads. Partitions that would exceed the memory limit
+are split again with a differently seeded hash before loading. Those reads are consequently written after
+all others, but the order of the reads across all read files remains identical.
+*/
+
+const PARTITIONS: usize = 16;
+
+// Maximum number of times a spilled partition is split again, which bounds the recursion for records that can't
+// be separated, e.g. a single record larger than the memory limit.
+const MAX_SPLITS: u32 = 4;
+
+// Rough estimate of the allocation overhead per indexed record.
+const RECORD_OVERHEAD: usize = 128;
+
+pub struct UmiIndex {
+    records: HashMap<String, Record>,
+    spill: Option<Spill>,
+    // Records are indexed by their normalised IDs.
+    rules: Vec<IdRule>,
+    num_reads: usize,
+    memory_limit: usize,
+}
+
+struct Spill {
+    dir: TempDir,
+    umis: Vec<FastqWriter<File>>,
+    // One writer per read file and partition
+    deferred: Vec<Vec<FastqWriter<File>>>,
+    deferred_count: usize,
+    // Estimated memory needed to load each partition.
+    spilled: Vec<usize>,
+    // Number of splits that led to these partitions, which seeds the hash.
+    level: u32,
+}
+
+impl Spill {
+    fn new(tmp_dir: Option<&Path>, num_reads: usize, level: u32) -> Result<Self> {
+        let dir = match tmp_dir {
+            Some(path) => TempDir::new_in(path),
+            None => TempDir::new(),
+        }
+        .context("Failed to create temporary directory for spilling UMI records to disk")?;
+
+        let mut umis = Vec::with_capacity(PARTITIONS);
+        let mut deferred = Vec::with_capacity(PARTITIONS);
+        for partition in 0..PARTITIONS {
+            umis.push(FastqWriter::to_file(Spill::umi_path(
+                dir.path(),
+                partition,
+            ))?);
+            deferred.push(
+                (0..num_reads)
+                    .map(|read| FastqWriter::to_file(Spill::read_path(dir.path(), partition, read)))
+                    .collect::<Result<_, _>>()?,
+            );
+        }
+
+        Ok(Spill {
+            dir,
+            umis,
+            deferred,
+            deferred_count: 0,
+            spilled: vec![0; PARTITIONS],
+            level,
+        })
+    }
+
+    fn umi_path(dir: &Path, partition: usize) -> PathBuf {
+        dir.join(format!("umi_{partition}.fq"))
+    }
+
+    fn read_path(dir: &Path, partition: usize, read: usize) -> PathBuf {
+        dir.join(format!("r{}_{partition}.fq", read + 1))
+    }
+
+    fn partition(&self, id: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        self.level.hash(&mut hasher);
+        id.hash(&mut hasher);
+        (hasher.finish() % PARTITIONS as u64) as usize
+    }
+
+    fn spill_umi(&mut self, key: &str, umi: &Record) -> Result<()> {
+        let partition = self.partition(key);
+        self.spilled[partition] += record_size(umi);
+        write_spilled(&mut self.umis[partition], umi)
+    }
+
+    fn defer(&mut self, key: &str, reads: &[Record]) -> Result<()> {
+        let partition = self.partition(key);
+        for (writer, read) in self.deferred[partition].iter_mut().zip(reads) {
+            write_spilled(writer, read)?;
+        }
+        self.deferred_count += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for writer in self
+            .umis
+            .iter_mut()
+            .chain(self.deferred.iter_mut().flatten())
+        {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    // Distributes a partition that exceeds the memory limit across the partitions of a new spill.
+    fn split(&self, partition: usize, num_reads: usize, rules: &[IdRule]) -> Result<Spill> {
+        let mut split = Spill::new(Some(self.dir.path()), num_reads, self.level + 1)?;
+        let umi_path = Spill::umi_path(self.dir.path(), partition);
+        for umi_res in file_io::read_fastq(&umi_path)?.records() {
+            let umi = umi_res?;
+            split.spill_umi(normalise(umi.id(), rules), &umi)?;
+        }
+        for reads in file_io::LockstepRecords::new(self.deferred_reads(partition, num_reads)?) {
+            let reads = reads?;
+            split.defer(normalise(reads[0].id(), rules), &reads)?;
+        }
+        split.flush()?;
+        Ok(split)
+    }
+
+    fn deferred_reads(
+        &self,
+        partition: usize,
+        num_reads: usize,
+    ) -> Result<Vec<(PathBuf, file_io::InputRecords)>> {
+        (0..num_reads)
+            .map(|read| {
+                let path = Spill::read_path(self.dir.path(), partition, read);
+                Ok((path.clone(), file_io::read_fastq(&path)?.records().into()))
+            })
+            .collect()
+    }
+}
+
+fn record_size(umi: &Record) -> usize {
+    umi.id().len()
+        + umi.desc().map_or(0, str::len)
+        + umi.seq().len()
+        + umi.qual().len()
+        + RECORD_OVERHEAD
+}
+
+fn write_spilled(writer: &mut FastqWriter<File>, record: &Record) -> Result<()> {
+    writer
+        .write_record(record)
+        .map_err(|_| anyhow!(RuntimeErrors::ReadWriteError(record.clone())))
+}
+
+impl UmiIndex {
+    // Indexes all UMI records by their ID and spills them to disk once the memory limit (in bytes) is exceeded.
+    // `num_reads` is the number of read files, whose records share a UMI record.
+    pub fn build(
+        umis: impl IntoIterator<Item = Result<Record>>,
+        num_reads: usize,
+        memory_limit: usize,
+        tmp_dir: Option<&Path>,
+        rules: &[IdRule],
+    ) -> Result<Self> {
+        let mut index = UmiIndex {
+            records: HashMap::new(),
+            spill: None,
+            rules: rules.to_vec(),
+            num_reads,
+            memory_limit,
+        };
+        let mut memory_used: usize = 0;
+
+        for umi_res in umis {
+            let umi = umi_res?;
+            let size = record_size(&umi);
+            let key = normalise(umi.id(), rules);
+
+            // Duplicate IDs would silently assign the wrong UMI. Once spilling started, all further records are
+            // spilled, so that duplicates among the spilled records are found when their partition is loaded.
+            if index.records.contains_key(key) {
+                return Err(anyhow!(RuntimeErrors::DuplicateUmiID(umi.id().to_string())));
+            }
+            if index.spill.is_none() && memory_used + size <= memory_limit {
+                memory_used += size;
+                index.records.insert(key.to_string(), umi);
+            } else {
+                if index.spill.is_none() {
+                    index.spill = Some(Spill::new(tmp_dir, num_reads, 0)?);
+                }
+                index.spill.as_mut().unwrap().spill_umi(key, &umi)?;
+            }
+        }
+        Ok(index)
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    // Retrieves the UMI record for the reads or defers them, if their UMI might have been spilled to disk.
+    // The UMI record is looked up by the ID of the first read.
+    pub fn lookup_or_defer(
+        &mut self,
+        reads: Vec<Record>,
+    ) -> Result<Option<(Vec<Record>, &Record)>> {
+        let key = normalise(reads[0].id(), &self.rules);
+        if let Some(umi) = self.records.get(key) {
+            return Ok(Some((reads, umi)));
+        }
+        match self.spill.as_mut() {
+            Some(spill) => {
+                spill.defer(key, &reads)?;
+                Ok(None)
+            }
+            None => Err(anyhow!(RuntimeErrors::UmiNotFound(
+                reads[0].id().to_string()
+            ))),
+        }
+    }
+
+    // Loads the spilled partitions one by one and passes the deferred reads with their UMI record to `transfer`.
+    pub fn resolve_deferred<F>(self, mut transfer: F) -> Result<usize>
+    where
+        F: FnMut(Vec<Record>, &Record) -> Result<()>,
+    {
+        let Some(mut spill) = self.spill else {
+            return Ok(0);
+        };
+        // Free the memory of the in-memory index before loading the partitions.
+        drop(self.records);
+
+        spill.flush()?;
+        let deferred_count = spill.deferred_count;
+        resolve_spill(
+            &spill,
+            self.num_reads,
+            self.memory_limit,
+            &self.rules,
+            &mut transfer,
+        )?;
+        Ok(deferred_count)
+    }
+}
+
+// Resolves the deferred reads of all partitions. Partitions that exceed the memory limit are split again, so that
+// the memory limit also holds for UMI files much larger than it.
+fn resolve_spill<F>(
+    spill: &Spill,
+    num_reads: usize,
+    memory_limit: usize,
+    rules: &[IdRule],
+    transfer: &mut F,
+) -> Result<()>
+where
+    F: FnMut(Vec<Record>, &Record) -> Result<()>,
+{
+    for partition in 0..PARTITIONS {
+        if spill.spilled[partition] > memory_limit && spill.level < MAX_SPLITS {
+            let split = spill.split(partition, num_reads, rules)?;
+            resolve_spill(&split, num_reads, memory_limit, rules, transfer)?;
+            continue;
+        }
+
+        let umi_path = Spill::umi_path(spill.dir.path(), partition);
+        let mut umis: HashMap<String, Record> = HashMap::new();
+        for umi_res in file_io::read_fastq(&umi_path)?.records() {
+            let umi = umi_res?;
+            let key = normalise(umi.id(), rules).to_string();
+            if umis.contains_key(&key) {
+                return Err(anyhow!(RuntimeErrors::DuplicateUmiID(umi.id().to_string())));
+            }
+            umis.insert(key, umi);
+        }
+
+        for reads in file_io::LockstepRecords::new(spill.deferred_reads(partition, num_reads)?) {
+            let reads = reads?;
+            let umi = umis
+                .get(normalise(reads[0].id(), rules))
+                .ok_or_else(|| anyhow!(RuntimeErrors::UmiNotFound(reads[0].id().to_string())))?;
+            transfer(reads, umi)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use assert_fs::TempDir;
+
+    fn umi_records(temp_dir: &TempDir, ids: &[&str]) -> impl Iterator<Item = Result<Record>> {
+        let path = temp_dir.path().join("umi.fq");
+        let content: String = ids
+            .iter()
+            .map(|id| format!("@{id}\nACGT\n+\nFFFF\n"))
+            .collect();
+        std::fs::write(&path, content).unwrap();
+        file_io::read_fastq(&path)
+            .unwrap()
+            .records()
+            .map(|umi| Ok(umi?))
+    }
+
+    fn read(id: &str) -> Record {
+        Record::with_attrs(id, None, b"TTTT", b"FFFF")
+    }
+
+    #[test]
+    fn test_index_in_memory() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut index =
+            UmiIndex::build(umi_records(&temp_dir, &["b", "a"]), 2, 1 << 20, None, &[]).unwrap();
+        assert!(!index.is_spilled());
+
+        let (reads, umi) = index
+            .lookup_or_defer(vec![read("a"), read("a")])
+            .unwrap()
+            .unwrap();
+        assert_eq!(reads[0].id(), umi.id());
+        assert!(index.lookup_or_defer(vec![read("c"), read("c")]).is_err());
+    }
+
+    #[test]
+    fn test_index_spills_to_disk() {
+        let temp_dir = TempDir::new().unwrap();
+        let ids = ["a", "b", "c", "d", "e"];
+        // Memory for exactly one record
+        let mut index = UmiIndex::build(
+            umi_records(&temp_dir, &ids),
+            2,
+            RECORD_OVERHEAD + 10,
+            Some(temp_dir.path()),
+            &[],
+        )
+        .unwrap();
+        assert!(index.is_spilled());
+
+        let mut resolved = Vec::new();
+        for id in ids.iter().rev() {
+            if let Some((reads, _umi)) = index.lookup_or_defer(vec![read(id), read(id)]).unwrap() {
+                resolved.push(reads[0].id().to_string());
+            }
+        }
+        assert_eq!(resolved, ["a"]);
+
+        let deferred = index
+            .resolve_deferred(|reads, umi| {
+                assert_eq!(reads.len(), 2);
+                assert!(reads.iter().all(|read| read.id() == umi.id()));
+                resolved.push(reads[0].id().to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(deferred, 4);
+        resolved.sort();
+        assert_eq!(resolved, ids);
+    }
+
+    #[test]
+    fn test_index_rejects_duplicate_ids() {
+        let temp_dir = TempDir::new().unwrap();
+        let err = UmiIndex::build(
+            umi_records(&temp_dir, &["a", "b", "a"]),
+            2,
+            1 << 20,
+            None,
+            &[],
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("several records with the ID a"));
+
+        // Duplicates among the spilled records are found when their partition is loaded.
+        let index = UmiIndex::build(
+            umi_records(&temp_dir, &["a", "b", "c", "b"]),
+            2,
+            RECORD_OVERHEAD + 10,
+            Some(temp_dir.path()),
+            &[],
+        )
+        .unwrap();
+        let err = index.resolve_deferred(|_, _| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("several records with the ID b"));
+    }
+
+    #[test]
+    fn test_index_splits_partitions_over_the_limit() {
+        let temp_dir = TempDir::new().unwrap();
+        let ids: Vec<String> = (0..400).map(|i| format!("read{i}")).collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        // Memory for two records, while each partition receives about 25 of them.
+        let memory_limit = 2 * (RECORD_OVERHEAD + 15);
+        let mut index = UmiIndex::build(
+            umi_records(&temp_dir, &ids),
+            1,
+            memory_limit,
+            Some(temp_dir.path()),
+            &[],
+        )
+        .unwrap();
+        let spill = index.spill.as_ref().unwrap();
+        let partition = (0..PARTITIONS).max_by_key(|&p| spill.spilled[p]).unwrap();
+        assert!(spill.spilled[partition] > memory_limit);
+
+        let mut resolved = 0;
+        for id in &ids {
+            if index.lookup_or_defer(vec![read(id)]).unwrap().is_some() {
+                resolved += 1;
+            }
+        }
+        assert_eq!(resolved, 2);
+        let spill = index.spill.as_mut().unwrap();
+        spill.flush().unwrap();
+        let split = spill.split(partition, 1, &[]).unwrap();
+        assert_eq!(split.level, 1);
+        assert_eq!(
+            split.spilled.iter().sum::<usize>(),
+            spill.spilled[partition]
+        );
+        assert!(split.deferred_count > 0);
+
+        let deferred = index
+            .resolve_deferred(|reads, umi| {
+                assert_eq!(reads[0].id(), umi.id());
+                resolved += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(deferred, 398);
+        assert_eq!(resolved, 400);
+    }
+}
@@ -0,0 +1,369 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use std::path::PathBuf;
+
+use super::file_io::{self, OutputCompression, OutputFormat};
+use crate::auxiliary::{status, threads_available, threads_per_task};
+use crate::read_structure::ReadStructure;
+use crate::ubam::{InputRole, ReadGroup};
+use crate::umi_errors::RuntimeErrors;
+use crate::umi_external::update_record;
+use crate::umi_header::{
+    build_formatter, default_format, HeaderFormat, HeaderPreset, HeaderTemplate,
+};
+#[derive(Debug, Parser)]
+#[clap(group(
+    clap::ArgGroup::new("umi_lengths")
+        .required(true)
+        .multiple(true)
+        .args(["umi_len", "umi_len2", "read_structure", "read_structure2"])
+))]
+pub struct OptsInternal {
+    #[clap(
+        short = 'c',
+        long = "correct_numbers",
+        help = "Read numbers will be altered to ensure the canonical read numbers 1 and 2 in output file sequence headers.
+        \n "
+    )]
+    edit_nr: bool,
+    #[clap(flatten)]
+    compression: OutputCompression,
+    #[clap(
+        short = 't',
+        long = "threads",
+        help = "Maximum number of threads to use for processing. Preferably pick odd numbers, 9 or 11 recommended. Defaults to the maximum number of cores available.
+        \n "
+    )]
+    num_threads: Option<usize>,
+    #[clap(
+        short = 'f',
+        long = "force",
+        help = "Overwrite existing output files without further warnings or prompts.
+        \n "
+    )]
+    force: bool,
+    #[clap(
+        short = 'd',
+        long = "delim",
+        help = "Delimiter to use when joining the UMIs to the read name. Defaults to `:`.
+        \n "
+    )]
+    delim: Option<String>,
+    #[clap(
+        long = "header_format",
+        value_enum,
+        help = "Where to write the UMI: `id` appends it to the read ID, `tags` writes it as SAM-style RX:Z and QX:Z tags into the description, e.g. for fgbio, bwa -C or Picard. Defaults to `id`.
+        \n "
+    )]
+    header_format: Option<HeaderFormat>,
+    #[clap(
+        long = "header_preset",
+        alias = "header-preset",
+        value_enum,
+        conflicts_with_all = ["delim", "header_format"],
+        help = "Header layout expected by a downstream tool: `umi_tools` appends the UMI to the read ID with `_`, `bcl_convert` and `illumina` write it as 8th colon-separated field of the read ID, `fgbio` and `picard` write RX:Z and QX:Z tags.
+        \n "
+    )]
+    header_preset: Option<HeaderPreset>,
+    #[clap(
+        long = "header_template",
+        alias = "header-template",
+        conflicts_with_all = ["delim", "header_format", "header_preset"],
+        help = "Custom header layout, e.g. `{id}_{umi} {comment}` or `{id} {readnr}:N:0:{index}+{umi}`. Placeholders are {id}, {comment}, {umi}, {umi_qual}, {readnr} and {index}, the latter two are parsed from the Illumina comment. The first space separates the read ID from the comment.
+        \n "
+    )]
+    header_template: Option<HeaderTemplate>,
+    #[clap(
+        long = "output_format",
+        value_enum,
+        help = "Write one FastQ file per read (`fastq`), a single FastQ file with the reads of each fragment alternating (`interleaved`), e.g. for bwa mem -p, or a single unaligned BAM file (`bam`) with the reads of each fragment as mates and the UMI as RX and QX tags, e.g. for fgbio or GATK. BAM output is always BGZF-compressed. Defaults to `fastq`.
+        \n "
+    )]
+    output_format: Option<OutputFormat>,
+    #[clap(
+        long = "read_group",
+        help = "Read group of the BAM output, e.g. `ID:rg1\\tSM:sample1\\tPL:ILLUMINA`. All records are assigned to it. Defaults to the ID `A` and a sample named after input file 1.
+        \n "
+    )]
+    read_group: Option<ReadGroup>,
+    #[clap(
+        long = "umi_length",
+        help = "Length of the UMI at the 5' end of read 1. At least one UMI length or read structure is required.
+        \n "
+    )]
+    umi_len: Option<usize>,
+    #[clap(
+        long = "umi_length2",
+        help = "Length of the UMI at the 5' end of read 2. If both reads carry a UMI, they are concatenated in the read name.
+        \n "
+    )]
+    umi_len2: Option<usize>,
+    #[clap(
+        long = "read_structure",
+        conflicts_with = "umi_len",
+        help = "Read structure of read 1, e.g. `8M2S+T` for an 8 base UMI (M), followed by 2 skipped bases (S) and the template (T). Alternative to --umi_length.
+        \n "
+    )]
+    read_structure: Option<ReadStructure>,
+    #[clap(
+        long = "read_structure2",
+        conflicts_with = "umi_len2",
+        help = "Read structure of read 2. Alternative to --umi_length2.
+        \n "
+    )]
+    read_structure2: Option<ReadStructure>,
+    #[clap(
+        long = "in",
+        required = true,
+        help = "[REQUIRED] Input file 1 with reads.
+    \n "
+    )]
+    r1_in: PathBuf,
+    #[clap(
+        long = "in2",
+        required = true,
+        help = "[REQUIRED] Input file 2 with reads.
+    \n "
+    )]
+    r2_in: PathBuf,
+    #[clap(
+        long = "out",
+        help = "Path to FastQ output file for R1.
+    \n "
+    )]
+    r1_out: Option<PathBuf>,
+    #[clap(
+        long = "out2",
+        help = "Path to FastQ output file for R2.
+    \n "
+    )]
+    r2_out: Option<PathBuf>,
+}
+
+impl OptsInternal {
+    // Whether any of the outputs is written to stdout.
+    pub fn writes_to_stdout(&self) -> bool {
+        [&self.r1_out, &self.r2_out]
+            .iter()
+            .any(|out| out.as_deref().is_some_and(file_io::is_stdio))
+    }
+}
+
+pub fn run(args: OptsInternal) -> Result<i32> {
+    // Set the number of threads to max, unless manually specified. In case of failure, use only 1.
+    let num_threads = args.num_threads.unwrap_or_else(threads_available);
+
+    // Determine the number of threads available for output file compression.
+    let output_format = args.output_format.unwrap_or(OutputFormat::Fastq);
+    if args.read_group.is_some() && output_format != OutputFormat::Bam {
+        return Err(anyhow!(RuntimeErrors::ReadGroupWithoutBam));
+    }
+    let tasks = match output_format {
+        OutputFormat::Bam | OutputFormat::Interleaved => 1,
+        OutputFormat::Fastq => 2,
+    };
+    let threads_per_task = threads_per_task(num_threads, tasks);
+
+    // A plain UMI length is a shorthand for the read structure `<length>M+T`.
+    let structure1 = args
+        .read_structure
+        .unwrap_or_else(|| ReadStructure::inline_umi(args.umi_len.unwrap_or(0)));
+    let structure2 = args
+        .read_structure2
+        .unwrap_or_else(|| ReadStructure::inline_umi(args.umi_len2.unwrap_or(0)));
+
+    let formatter = build_formatter(
+        args.header_template,
+        args.header_preset,
+        default_format(args.header_format, args.delim.as_deref(), output_format),
+        args.delim.as_deref(),
+    );
+
+    // Stdin and stdout may only be used once each.
+    file_io::check_stdio(
+        [&args.r1_in, &args.r2_in],
+        args.r1_out.iter().chain(&args.r2_out),
+    )?;
+
+    // Read records from input files
+    let r1 = file_io::read_records(&args.r1_in, InputRole::Read1).with_context(|| {
+        format!(
+            "Failed to read records from {}",
+            &args.r1_in.to_string_lossy()
+        )
+    })?;
+    let r2 = file_io::read_records(&args.r2_in, InputRole::Read2).with_context(|| {
+        format!(
+            "Failed to read records from {}",
+            &args.r2_in.to_string_lossy()
+        )
+    })?;
+
+    let mut writers = Vec::with_capacity(2);
+    match output_format {
+        // Both reads are written to the same BAM file.
+        OutputFormat::Bam => writers.push(file_io::create_bam_output(
+            &[
+                (args.r1_in.clone(), args.r1_out),
+                (args.r2_in.clone(), args.r2_out),
+            ],
+            &args.compression.gzi,
+            &args.force,
+            &threads_per_task,
+            &args.compression.compression_level,
+            args.read_group,
+        )?),
+        // Both reads are written alternately to the same FastQ file.
+        OutputFormat::Interleaved => writers.push(file_io::create_interleaved_output(
+            &[
+                (args.r1_in.clone(), args.r1_out),
+                (args.r2_in.clone(), args.r2_out),
+            ],
+            &args.compression.gzip,
+            &args.compression.compression_format,
+            &args.compression.gzi,
+            &args.force,
+            &threads_per_task,
+            &args.compression.compression_level,
+        )?),
+        OutputFormat::Fastq => {
+            // If output paths have been specified, check if the are ok to use or use prefix constructors.
+            let mut output1: PathBuf = file_io::resolve_outputpath(args.r1_out, &args.r1_in)?;
+            let mut output2: PathBuf = file_io::resolve_outputpath(args.r2_out, &args.r2_in)?;
+
+            // set the correct extension.
+            output1 = file_io::rectify_extension(
+                output1,
+                &args.compression.gzip,
+                &args.compression.compression_format,
+            )?;
+            output2 = file_io::rectify_extension(
+                output2,
+                &args.compression.gzip,
+                &args.compression.compression_format,
+            )?;
+
+            // modify if output path according to compression settings and check if exists.
+            output1 = file_io::check_outputpath(output1, &args.force)?;
+            output2 = file_io::check_outputpath(output2, &args.force)?;
+
+            status!("Output 1 will be saved to: {}", output1.to_string_lossy());
+            status!("Output 2 will be saved to: {}", output2.to_string_lossy());
+
+            for output in [output1, output2] {
+                writers.push(file_io::create_writer(
+                    output,
+                    &args.compression.gzip,
+                    &args.compression.compression_format,
+                    &args.compression.gzi,
+                    &threads_per_task,
+                    &args.compression.compression_level,
+                    None,
+                )?);
+            }
+        }
+    }
+
+    // Record counter
+    let mut counter: i32 = 0;
+
+    status!("Extracting UMIs from records...");
+
+    // Iterate over records in input files, which must both contain the same number of records.
+    let inputs =
+        file_io::LockstepRecords::new(vec![(args.r1_in.clone(), r1), (args.r2_in.clone(), r2)]);
+    for records in inputs {
+        let [r1_rec, r2_rec]: [bio::io::fastq::Record; 2] = records?.try_into().unwrap();
+
+        // Step counter
+        counter += 1;
+
+        if !r1_rec.id().eq(r2_rec.id()) {
+            return Err(anyhow!(RuntimeErrors::ReadPairMismatch));
+        }
+
+        // Remove the UMIs from the reads and concatenate them, if both reads carry one.
+        let (r1_rec, mut umi, mut umi_qual) = split_umi(r1_rec, &structure1)?;
+        let (r2_rec, umi2, umi_qual2) = split_umi(r2_rec, &structure2)?;
+        umi.extend_from_slice(&umi2);
+        umi_qual.extend_from_slice(&umi_qual2);
+
+        // Write to Output file
+        let read_nr = if args.edit_nr { Some(1) } else { None };
+        let r1_rec = update_record(r1_rec, &umi, &umi_qual, &*formatter, read_nr)?;
+        writers[0].write_record(r1_rec)?;
+
+        let read_nr = if args.edit_nr { Some(2) } else { None };
+        let r2_rec = update_record(r2_rec, &umi, &umi_qual, &*formatter, read_nr)?;
+        // There is either one writer per read or a single BAM or interleaved writer.
+        let last = writers.len() - 1;
+        writers[last].write_record(r2_rec)?;
+    }
+    // Complete the compressed streams, so that errors while writing them are reported.
+    for writer in writers {
+        writer.finish()?;
+    }
+    status!("Processed {:?} records", counter);
+    Ok(counter)
+}
+
+// Reduces a record to its template segments and returns the sequence and quality of the UMI segments separately.
+fn split_umi(
+    input: bio::io::fastq::Record,
+    structure: &ReadStructure,
+) -> Result<(bio::io::fastq::Record, Vec<u8>, Vec<u8>)> {
+    let extracted = structure.extract(&input)?;
+    let trimmed = bio::io::fastq::Record::with_attrs(
+        input.id(),
+        input.desc(),
+        &extracted.template_seq,
+        &extracted.template_qual,
+    );
+    Ok((trimmed, extracted.umi_seq, extracted.umi_qual))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_split_umi_trims_sequence_and_quality() {
+        let record = bio::io::fastq::Record::with_attrs(
+            "read1",
+            Some("1:N:0:ACGT"),
+            b"ACGTTTTT",
+            b"ABCDEFGH",
+        );
+        let (trimmed, umi, umi_qual) = split_umi(record, &ReadStructure::inline_umi(4)).unwrap();
+        assert_eq!(umi, b"ACGT");
+        assert_eq!(umi_qual, b"ABCD");
+        assert_eq!(trimmed.seq(), b"TTTT");
+        assert_eq!(trimmed.qual(), b"EFGH");
+        assert_eq!(trimmed.desc(), Some("1:N:0:ACGT"));
+    }
+
+    #[test]
+    fn test_split_umi_with_zero_length_keeps_record() {
+        let record = bio::io::fastq::Record::with_attrs("read1", None, b"ACGT", b"ABCD");
+        let (trimmed, umi, _) = split_umi(record, &ReadStructure::inline_umi(0)).unwrap();
+        assert!(umi.is_empty());
+        assert_eq!(trimmed.seq(), b"ACGT");
+    }
+
+    #[test]
+    fn test_split_umi_skips_spacer() {
+        let record =
+            bio::io::fastq::Record::with_attrs("read1", None, b"ACGTNNTTTT", b"ABCDEFGHIJ");
+        let (trimmed, umi, _) = split_umi(record, &"4M2S+T".parse().unwrap()).unwrap();
+        assert_eq!(umi, b"ACGT");
+        assert_eq!(trimmed.seq(), b"TTTT");
+        assert_eq!(trimmed.qual(), b"GHIJ");
+    }
+
+    #[test]
+    fn test_split_umi_fails_on_short_read() {
+        let record = bio::io::fastq::Record::with_attrs("read1", None, b"ACG", b"ABC");
+        assert!(split_umi(record, &ReadStructure::inline_umi(4)).is_err());
+    }
+}
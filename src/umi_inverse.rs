@@ -0,0 +1,377 @@
+////////////////////////////////////////////////////////////////
+//  UMIS FROM THE HEADERS TO A SEPARATE FASTQ FILE
+////////////////////////////////////////////////////////////////
+
+/*
+The inverse of the `external` subcommand: UMIs that are already embedded into the read headers are parsed
+with the same header formatters that write them, removed from the reads and written to a separate UMI FastQ.
+This also allows to undo a wrong delimiter by running `external` with the right one afterwards.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use bio::{alphabets::dna::revcomp, io::fastq::Record};
+use clap::Parser;
+use std::path::PathBuf;
+
+use super::file_io::{self, OutputCompression};
+use crate::auxiliary::{status, threads_available, threads_per_task};
+use crate::ubam::InputRole;
+use crate::umi_errors::RuntimeErrors;
+use crate::umi_header::{
+    build_formatter, is_nucleotide_umi, HeaderFormat, HeaderFormatter, HeaderPreset,
+    HeaderTemplate, ParsedHeader,
+};
+
+#[derive(Debug, Parser)]
+pub struct OptsInverse {
+    #[clap(flatten)]
+    compression: OutputCompression,
+    #[clap(
+        short = 't',
+        long = "threads",
+        help = "Maximum number of threads to use for processing. Preferably pick odd numbers, 9 or 11 recommended. Defaults to the maximum number of cores available.
+        \n "
+    )]
+    num_threads: Option<usize>,
+    #[clap(
+        short = 'f',
+        long = "force",
+        help = "Overwrite existing output files without further warnings or prompts.
+        \n "
+    )]
+    force: bool,
+    #[clap(
+        short = 'd',
+        long = "delim",
+        help = "Delimiter between the read name and the UMI in the input files. Defaults to `:`.
+        \n "
+    )]
+    delim: Option<String>,
+    #[clap(
+        long = "header_format",
+        value_enum,
+        help = "Where the UMI is found: `id` at the end of the read ID, `tags` as SAM-style RX:Z and QX:Z tags in the description. Defaults to `id`.
+        \n "
+    )]
+    header_format: Option<HeaderFormat>,
+    #[clap(
+        long = "header_preset",
+        alias = "header-preset",
+        value_enum,
+        conflicts_with_all = ["delim", "header_format"],
+        help = "Header layout of the input files, as written by the presets of the `external` subcommand.
+        \n "
+    )]
+    header_preset: Option<HeaderPreset>,
+    #[clap(
+        long = "header_template",
+        alias = "header-template",
+        conflicts_with_all = ["delim", "header_format", "header_preset"],
+        help = "Custom header layout of the input files, e.g. `{id}_{umi} {comment}`. Only {id}, {comment}, {umi} and {umi_qual} are restored, the values of all other placeholders are dropped.
+        \n "
+    )]
+    header_template: Option<HeaderTemplate>,
+    #[clap(
+        long = "umi_quality",
+        help = "Quality character written for each UMI base, unless the header preserved the UMI qualities. Defaults to `I`.
+        \n "
+    )]
+    umi_quality: Option<char>,
+    #[clap(
+        long = "in",
+        required = true,
+        help = "[REQUIRED] Input file 1 with reads.
+    \n "
+    )]
+    r1_in: PathBuf,
+    #[clap(
+        long = "in2",
+        help = "Input file 2 with reads. Omit for single-end data.
+    \n "
+    )]
+    r2_in: Option<PathBuf>,
+    #[clap(
+        long = "out",
+        help = "Path to FastQ output file for R1 without UMIs.
+    \n "
+    )]
+    r1_out: Option<PathBuf>,
+    #[clap(
+        long = "out2",
+        requires = "r2_in",
+        help = "Path to FastQ output file for R2 without UMIs.
+    \n "
+    )]
+    r2_out: Option<PathBuf>,
+    #[clap(
+        long = "umi_out",
+        help = "Path to FastQ output file for the UMIs. Defaults to the name of input file 1 with an `_UMIs` suffix.
+    \n "
+    )]
+    ru_out: Option<PathBuf>,
+}
+
+impl OptsInverse {
+    // Whether any of the outputs is written to stdout.
+    pub fn writes_to_stdout(&self) -> bool {
+        [&self.r1_out, &self.r2_out, &self.ru_out]
+            .iter()
+            .any(|out| out.as_deref().is_some_and(file_io::is_stdio))
+    }
+}
+
+pub fn run(args: OptsInverse) -> Result<i32> {
+    let umi_quality = args.umi_quality.unwrap_or('I');
+    if !umi_quality.is_ascii_graphic() {
+        return Err(anyhow!(
+            "The UMI quality must be a printable ASCII character, but is {:?}.",
+            umi_quality
+        ));
+    }
+
+    let formatter = build_formatter(
+        args.header_template,
+        args.header_preset,
+        args.header_format,
+        args.delim.as_deref(),
+    );
+
+    // Pair each read file with its output path. Single-end data only has a single read file.
+    let mut reads = vec![(args.r1_in.clone(), args.r1_out.clone())];
+    if let Some(r2_in) = &args.r2_in {
+        reads.push((r2_in.clone(), args.r2_out.clone()));
+    }
+
+    // Set the number of threads to max, unless manually specified. In case of failure, use only 1.
+    let num_threads = args.num_threads.unwrap_or_else(threads_available);
+
+    // Determine the number of threads available for output file compression. The UMI file is written as well.
+    let threads_per_task = threads_per_task(num_threads, reads.len() + 1);
+
+    // Stdin and stdout may only be used once each.
+    file_io::check_stdio(
+        reads.iter().map(|(input, _)| input),
+        reads
+            .iter()
+            .filter_map(|(_, output)| output.as_ref())
+            .chain(&args.ru_out),
+    )?;
+
+    // Read records from input files
+    let mut inputs = Vec::with_capacity(reads.len());
+    for (i, (input, _)) in reads.iter().enumerate() {
+        let role = if i == 0 {
+            InputRole::Read1
+        } else {
+            InputRole::Read2
+        };
+        let records = file_io::read_records(input, role)
+            .with_context(|| format!("Failed to read records from {}", input.to_string_lossy()))?;
+        inputs.push((input.clone(), records));
+    }
+
+    let mut outputs = Vec::with_capacity(reads.len() + 1);
+    for (input, output) in reads {
+        outputs.push(file_io::resolve_outputpath_with_suffix(
+            output,
+            &input,
+            "_without_UMIs",
+        )?);
+    }
+    // The UMIs are written after the reads, named after the first read file.
+    outputs.push(file_io::resolve_outputpath_with_suffix(
+        args.ru_out.clone(),
+        &args.r1_in,
+        "_UMIs",
+    )?);
+
+    let mut writers = Vec::with_capacity(outputs.len());
+    let umi_file = outputs.len() - 1;
+    for (i, mut output) in outputs.into_iter().enumerate() {
+        // set the correct extension.
+        output = file_io::rectify_extension(
+            output,
+            &args.compression.gzip,
+            &args.compression.compression_format,
+        )?;
+
+        // modify if output path according to compression settings and check if exists.
+        output = file_io::check_outputpath(output, &args.force)?;
+
+        if i == umi_file {
+            status!("UMI output will be saved to: {}", output.to_string_lossy());
+        } else {
+            status!(
+                "Output {} will be saved to: {}",
+                i + 1,
+                output.to_string_lossy()
+            );
+        }
+
+        writers.push(file_io::create_writer(
+            output,
+            &args.compression.gzip,
+            &args.compression.compression_format,
+            &args.compression.gzi,
+            &threads_per_task,
+            &args.compression.compression_level,
+            None,
+        )?);
+    }
+    let mut write_output_umi = writers.pop().unwrap();
+
+    // Record counter
+    let mut counter: i32 = 0;
+
+    status!("Moving UMIs from the headers to a separate file...");
+
+    // Iterate over records in input files, which must all contain the same number of records.
+    for records in file_io::LockstepRecords::new(inputs) {
+        let records = records?;
+
+        // Step counter
+        counter += 1;
+
+        let mut umi_rec = None;
+        for (rec, writer) in records.into_iter().zip(writers.iter_mut()) {
+            let (rec, umi) = split_header(rec, &*formatter, umi_quality)?;
+            match &umi_rec {
+                None => umi_rec = Some(umi),
+                Some(first) if first.id() != rec.id() => {
+                    return Err(anyhow!(RuntimeErrors::ReadPairMismatch))
+                }
+                Some(_) => {}
+            }
+            writer.write_record(rec)?;
+        }
+        write_output_umi.write_record(umi_rec.unwrap())?;
+    }
+    // Complete the compressed streams, so that errors while writing them are reported.
+    for writer in writers {
+        writer.finish()?;
+    }
+    write_output_umi.finish()?;
+    status!("Processed {:?} records", counter);
+    Ok(counter)
+}
+
+// Removes the UMI from the header of a record and returns it as separate record. UMIs that were reverse
+// complemented with an `r` prefix are restored to their original orientation.
+fn split_header(
+    input: Record,
+    formatter: &dyn HeaderFormatter,
+    umi_quality: char,
+) -> Result<(Record, Record)> {
+    let ParsedHeader {
+        id,
+        desc,
+        umi,
+        umi_qual,
+    } = formatter
+        .parse(input.id(), input.desc())
+        // Otherwise, e.g. the y coordinate of a plain Illumina read ID would be taken for the UMI.
+        .filter(|parsed| is_nucleotide_umi(&parsed.umi))
+        .ok_or_else(|| anyhow!(RuntimeErrors::UmiNotInHeader(input.id().to_string())))?;
+
+    // The separator of dual UMIs is no base and the UMI file of each half is unknown.
+    if umi.contains(['-', '+']) {
+        return Err(anyhow!(RuntimeErrors::DualUmiInHeader(
+            input.id().to_string()
+        )));
+    }
+    let (umi, reversed) = match umi.strip_prefix('r') {
+        Some(bases) => (bases, true),
+        None => (umi.as_str(), false),
+    };
+
+    // Qualities are only preserved, if there is one for each UMI base.
+    let mut umi_qual = umi_qual
+        .filter(|qual| qual.len() == umi.len())
+        .unwrap_or_else(|| umi_quality.to_string().repeat(umi.len()))
+        .into_bytes();
+    let mut umi_seq = umi.as_bytes().to_vec();
+    if reversed {
+        umi_seq = revcomp(umi_seq);
+        umi_qual.reverse();
+    }
+
+    let umi_rec = Record::with_attrs(&id, desc.as_deref(), &umi_seq, &umi_qual);
+    let cleaned = Record::with_attrs(&id, desc.as_deref(), input.seq(), input.qual());
+    Ok((cleaned, umi_rec))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::umi_header::SamTags;
+
+    #[test]
+    fn test_split_header_with_placeholder_qualities() {
+        let record = Record::with_attrs("read:1:ACGT", Some("1:N:0:GGCC"), b"TTTT", b"FFFF");
+        let formatter = HeaderTemplate::id_suffix(":");
+        let (cleaned, umi) = split_header(record, &formatter, 'I').unwrap();
+        assert_eq!(cleaned.id(), "read:1");
+        assert_eq!(cleaned.desc(), Some("1:N:0:GGCC"));
+        assert_eq!(cleaned.seq(), b"TTTT");
+        assert_eq!(umi.id(), "read:1");
+        assert_eq!(umi.seq(), b"ACGT");
+        assert_eq!(umi.qual(), b"IIII");
+    }
+
+    #[test]
+    fn test_split_header_preserves_qualities() {
+        let record = Record::with_attrs(
+            "read",
+            Some("1:N:0:GGCC\tRX:Z:ACGT\tQX:Z:AB:C"),
+            b"TTTT",
+            b"FFFF",
+        );
+        let (cleaned, umi) = split_header(record, &SamTags, 'I').unwrap();
+        assert_eq!(cleaned.desc(), Some("1:N:0:GGCC"));
+        assert_eq!(umi.seq(), b"ACGT");
+        assert_eq!(umi.qual(), b"AB:C");
+    }
+
+    #[test]
+    fn test_split_header_fails_for_coordinates() {
+        let record = Record::with_attrs("INSTR:500:FC:1:1101:19994:1016", None, b"TTTT", b"FFFF");
+        let formatter = HeaderTemplate::id_suffix(":");
+        let err = split_header(record, &formatter, 'I').unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("No UMI found in the header of read INSTR:500:FC:1:1101:19994:1016."));
+    }
+
+    #[test]
+    fn test_split_header_fails_without_umi() {
+        let record = Record::with_attrs("read", Some("1:N:0:GGCC"), b"TTTT", b"FFFF");
+        let err = split_header(record, &SamTags, 'I').unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("No UMI found in the header of read read."));
+    }
+
+    #[test]
+    fn test_split_header_restores_reverse_complemented_umi() {
+        let record = Record::with_attrs(
+            "read",
+            Some("1:N:0:GGCC\tRX:Z:rAACG\tQX:Z:ABCD"),
+            b"TTTT",
+            b"FFFF",
+        );
+        let (_, umi) = split_header(record, &SamTags, 'I').unwrap();
+        assert_eq!(umi.seq(), b"CGTT");
+        assert_eq!(umi.qual(), b"DCBA");
+    }
+
+    #[test]
+    fn test_split_header_fails_for_dual_umis() {
+        let formatter = HeaderTemplate::id_suffix(":");
+        for id in ["read:1:ACGT-TTGG", "read:1:rACGT+TTGG"] {
+            let record = Record::with_attrs(id, None, b"TTTT", b"FFFF");
+            let err = split_header(record, &formatter, 'I').unwrap_err();
+            assert!(err.to_string().contains("carries a dual UMI"));
+        }
+    }
+}
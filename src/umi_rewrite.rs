@@ -0,0 +1,293 @@
+////////////////////////////////////////////////////////////////
+//  REWRITING EMBEDDED UMIS
+////////////////////////////////////////////////////////////////
+
+/*
+Reads that were processed with the wrong delimiter or for another downstream tool already carry their UMI in
+the header. The UMI is detected with the header formatter of the old layout and must consist of nucleotides,
+so that e.g. the y coordinate of an Illumina read ID is not mistaken for a UMI. It is then either removed or
+embedded again with a new delimiter. Headers without a detectable UMI are written unchanged.
+*/
+
+use anyhow::{anyhow, Context, Result};
+use bio::io::fastq::Record;
+use clap::Parser;
+use std::path::PathBuf;
+
+use super::file_io::{self, OutputCompression};
+use crate::auxiliary::{status, threads_available, threads_per_task};
+use crate::ubam::InputRole;
+use crate::umi_errors::RuntimeErrors;
+use crate::umi_external::update_record;
+use crate::umi_header::{
+    build_formatter, is_nucleotide_umi, HeaderFormat, HeaderFormatter, HeaderPreset,
+    HeaderTemplate, ParsedHeader,
+};
+
+#[derive(Debug, Parser)]
+#[clap(group(
+    clap::ArgGroup::new("action")
+        .required(true)
+        .args(["strip", "new_delim"])
+))]
+pub struct OptsRewrite {
+    #[clap(flatten)]
+    compression: OutputCompression,
+    #[clap(
+        short = 't',
+        long = "threads",
+        help = "Maximum number of threads to use for processing. Preferably pick odd numbers, 9 or 11 recommended. Defaults to the maximum number of cores available.
+        \n "
+    )]
+    num_threads: Option<usize>,
+    #[clap(
+        short = 'f',
+        long = "force",
+        help = "Overwrite existing output files without further warnings or prompts.
+        \n "
+    )]
+    force: bool,
+    #[clap(
+        short = 'd',
+        long = "delim",
+        help = "Delimiter between the read name and the UMI in the input files. Defaults to `:`.
+        \n "
+    )]
+    delim: Option<String>,
+    #[clap(
+        long = "header_format",
+        value_enum,
+        help = "Where the UMI is found: `id` at the end of the read ID, `tags` as SAM-style RX:Z and QX:Z tags in the description. Defaults to `id`.
+        \n "
+    )]
+    header_format: Option<HeaderFormat>,
+    #[clap(
+        long = "header_preset",
+        alias = "header-preset",
+        value_enum,
+        conflicts_with_all = ["delim", "header_format"],
+        help = "Header layout of the input files, as written by the presets of the `external` subcommand.
+        \n "
+    )]
+    header_preset: Option<HeaderPreset>,
+    #[clap(
+        long = "header_template",
+        alias = "header-template",
+        conflicts_with_all = ["delim", "header_format", "header_preset"],
+        help = "Custom header layout of the input files, e.g. `{id}_{umi} {comment}`. Only {id}, {comment} and {umi} are kept, the values of all other placeholders are dropped.
+        \n "
+    )]
+    header_template: Option<HeaderTemplate>,
+    #[clap(
+        long = "strip",
+        help = "Remove the UMIs from the headers.
+        \n "
+    )]
+    strip: bool,
+    #[clap(
+        long = "new_delim",
+        help = "Embed the UMIs again, joined to the read name with this delimiter.
+        \n "
+    )]
+    new_delim: Option<String>,
+    #[clap(
+        long = "in",
+        required = true,
+        help = "[REQUIRED] Input file 1 with reads.
+    \n "
+    )]
+    r1_in: PathBuf,
+    #[clap(
+        long = "in2",
+        help = "Input file 2 with reads. Omit for single-end data.
+    \n "
+    )]
+    r2_in: Option<PathBuf>,
+    #[clap(
+        long = "out",
+        help = "Path to FastQ output file for R1.
+    \n "
+    )]
+    r1_out: Option<PathBuf>,
+    #[clap(
+        long = "out2",
+        requires = "r2_in",
+        help = "Path to FastQ output file for R2.
+    \n "
+    )]
+    r2_out: Option<PathBuf>,
+}
+
+impl OptsRewrite {
+    // Whether any of the outputs is written to stdout.
+    pub fn writes_to_stdout(&self) -> bool {
+        [&self.r1_out, &self.r2_out]
+            .iter()
+            .any(|out| out.as_deref().is_some_and(file_io::is_stdio))
+    }
+}
+
+pub fn run(args: OptsRewrite) -> Result<i32> {
+    // Layout of the input headers and, unless the UMIs are stripped, of the output headers.
+    let old_formatter = build_formatter(
+        args.header_template,
+        args.header_preset,
+        args.header_format,
+        args.delim.as_deref(),
+    );
+    let new_formatter = args.new_delim.as_deref().map(HeaderTemplate::id_suffix);
+
+    // Pair each read file with its output path. Single-end data only has a single read file.
+    let mut reads = vec![(args.r1_in.clone(), args.r1_out.clone())];
+    if let Some(r2_in) = &args.r2_in {
+        reads.push((r2_in.clone(), args.r2_out.clone()));
+    }
+
+    // Set the number of threads to max, unless manually specified. In case of failure, use only 1.
+    let num_threads = args.num_threads.unwrap_or_else(threads_available);
+
+    // Determine the number of threads available for output file compression.
+    let threads_per_task = threads_per_task(num_threads, reads.len());
+
+    // Stdin and stdout may only be used once each.
+    file_io::check_stdio(
+        reads.iter().map(|(input, _)| input),
+        reads.iter().filter_map(|(_, output)| output.as_ref()),
+    )?;
+
+    // Read records from input files
+    let mut inputs = Vec::with_capacity(reads.len());
+    for (i, (input, _)) in reads.iter().enumerate() {
+        let role = if i == 0 {
+            InputRole::Read1
+        } else {
+            InputRole::Read2
+        };
+        let records = file_io::read_records(input, role)
+            .with_context(|| format!("Failed to read records from {}", input.to_string_lossy()))?;
+        inputs.push((input.clone(), records));
+    }
+
+    let mut writers = Vec::with_capacity(reads.len());
+    for (i, (input, output)) in reads.into_iter().enumerate() {
+        // If output paths have been specified, check if the are ok to use or use prefix constructors.
+        // The inputs usually carry the `_with_UMIs` suffix already, hence it is not appended again.
+        let suffix = if args.strip {
+            "_without_UMIs"
+        } else {
+            "_rewritten"
+        };
+        let mut output: PathBuf = file_io::resolve_outputpath_with_suffix(output, &input, suffix)?;
+
+        // set the correct extension.
+        output = file_io::rectify_extension(
+            output,
+            &args.compression.gzip,
+            &args.compression.compression_format,
+        )?;
+
+        // modify if output path according to compression settings and check if exists.
+        output = file_io::check_outputpath(output, &args.force)?;
+
+        status!(
+            "Output {} will be saved to: {}",
+            i + 1,
+            output.to_string_lossy()
+        );
+
+        writers.push(file_io::create_writer(
+            output,
+            &args.compression.gzip,
+            &args.compression.compression_format,
+            &args.compression.gzi,
+            &threads_per_task,
+            &args.compression.compression_level,
+            None,
+        )?);
+    }
+
+    // Record counters
+    let mut counter: i32 = 0;
+    let mut unchanged: usize = 0;
+
+    status!("Rewriting UMIs in the headers...");
+
+    // Iterate over records in input files, which must all contain the same number of records.
+    for records in file_io::LockstepRecords::new(inputs) {
+        let records = records?;
+        // Step counter
+        counter += 1;
+
+        if records.iter().any(|rec| rec.id() != records[0].id()) {
+            return Err(anyhow!(RuntimeErrors::ReadPairMismatch));
+        }
+
+        for (rec, writer) in records.into_iter().zip(writers.iter_mut()) {
+            let rec = match detect_umi(&rec, &*old_formatter) {
+                Some(parsed) => {
+                    let cleaned = Record::with_attrs(
+                        &parsed.id,
+                        parsed.desc.as_deref(),
+                        rec.seq(),
+                        rec.qual(),
+                    );
+                    match &new_formatter {
+                        Some(formatter) => {
+                            let umi_qual = parsed.umi_qual.unwrap_or_default();
+                            update_record(
+                                cleaned,
+                                parsed.umi.as_bytes(),
+                                umi_qual.as_bytes(),
+                                formatter,
+                                None,
+                            )?
+                        }
+                        None => cleaned,
+                    }
+                }
+                None => {
+                    unchanged += 1;
+                    rec
+                }
+            };
+            writer.write_record(rec)?;
+        }
+    }
+    if unchanged > 0 {
+        status!(
+            "Left {} records without a detectable UMI unchanged",
+            unchanged
+        );
+    }
+    // Complete the compressed streams, so that errors while writing them are reported.
+    for writer in writers {
+        writer.finish()?;
+    }
+    status!("Processed {:?} records", counter);
+    Ok(counter)
+}
+
+// Parses the UMI from a header, provided that it consists of nucleotides.
+fn detect_umi(input: &Record, formatter: &dyn HeaderFormatter) -> Option<ParsedHeader> {
+    formatter
+        .parse(input.id(), input.desc())
+        .filter(|parsed| is_nucleotide_umi(&parsed.umi))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_detect_umi_ignores_coordinates() {
+        let formatter = HeaderTemplate::id_suffix(":");
+        let record = Record::with_attrs("INSTR:500:FC:1:1101:19994:1016", None, b"A", b"F");
+        assert!(detect_umi(&record, &formatter).is_none());
+
+        let record = Record::with_attrs("INSTR:500:FC:1:1101:19994:1016:ACGT", None, b"A", b"F");
+        let parsed = detect_umi(&record, &formatter).unwrap();
+        assert_eq!(parsed.id, "INSTR:500:FC:1:1101:19994:1016");
+        assert_eq!(parsed.umi, "ACGT");
+    }
+}
@@ -19,6 +19,20 @@ pub struct TestFiles {
     pub umi_gz: PathBuf,
     pub umi_shuffle: PathBuf,
     pub umi_shuffle_gz: PathBuf,
+    pub umi_truncated: PathBuf,
+    pub umi_unordered: PathBuf,
+    pub umi_mate_suffix: PathBuf,
+    pub index1: PathBuf,
+    pub index2: PathBuf,
+    pub umi2: PathBuf,
+    pub umi_duplex1: PathBuf,
+    pub umi_duplex2: PathBuf,
+    pub comment_umi: PathBuf,
+    pub bam_read1: PathBuf,
+    pub bam_read2: PathBuf,
+    pub bam_umi: PathBuf,
+    pub ubam_reads: PathBuf,
+    pub read_interleaved: PathBuf,
     pub nonexisting_output: PathBuf,
     pub new_output_read1: PathBuf,
     pub new_output_read1_gz: PathBuf,
@@ -42,6 +56,24 @@ pub struct TestOutput {
     pub delim_underscore_read2: PathBuf,
     pub umi_read2_switch_read1: PathBuf,
     pub umi_read2_switch_read2: PathBuf,
+    pub internal_read1: PathBuf,
+    pub internal_read2: PathBuf,
+    pub umi_structure_read1: PathBuf,
+    pub umi_structure_read2: PathBuf,
+    pub correct_index1: PathBuf,
+    pub correct_index2: PathBuf,
+    pub dual_umi_read1: PathBuf,
+    pub dual_umi_read2: PathBuf,
+    pub duplex_read1: PathBuf,
+    pub duplex_read2: PathBuf,
+    pub duplex_tags_read1: PathBuf,
+    pub duplex_tags_read2: PathBuf,
+    pub revcomp_read1: PathBuf,
+    pub revcomp_read2: PathBuf,
+    pub tags_read1: PathBuf,
+    pub tags_read2: PathBuf,
+    pub comment_read1: PathBuf,
+    pub correct_interleaved: PathBuf,
 }
 
 #[allow(dead_code)]
@@ -59,7 +91,7 @@ pub fn setup_integration_test(
             std::env::current_dir()
                 .expect("Failed to get directory")
                 .join("./tests/seqdata"),
-            &["*.fq", "*.gz"],
+            &["*.fq", "*.gz", "*.sam"],
         )
         .expect("Failed to copy test data to temporary directory.");
 
@@ -83,6 +115,20 @@ pub fn setup_integration_test(
         umi_gz: temp_dir.path().join("umi.fq.gz"),
         umi_shuffle: temp_dir.path().join("umi_shuffled.fq"),
         umi_shuffle_gz: temp_dir.path().join("umi_shuffled.fq.gz"),
+        umi_truncated: temp_dir.path().join("umi_truncated.fq"),
+        umi_unordered: temp_dir.path().join("umi_unordered.fq"),
+        umi_mate_suffix: temp_dir.path().join("umi_mate_suffix.fq"),
+        index1: temp_dir.path().join("index1.fq"),
+        index2: temp_dir.path().join("index2.fq"),
+        umi2: temp_dir.path().join("umi2.fq"),
+        umi_duplex1: temp_dir.path().join("umi_duplex1.fq"),
+        umi_duplex2: temp_dir.path().join("umi_duplex2.fq"),
+        comment_umi: temp_dir.path().join("comment_umi.fq"),
+        bam_read1: temp_dir.path().join("bam_read1.fq"),
+        bam_read2: temp_dir.path().join("bam_read2.fq"),
+        bam_umi: temp_dir.path().join("bam_umi.fq"),
+        ubam_reads: temp_dir.path().join("ubam_reads.sam"),
+        read_interleaved: temp_dir.path().join("read_interleaved.fq"),
         nonexisting_output: NamedTempFile::new("ACTG.fq").unwrap().path().to_path_buf(), //goes out of scope too early
         new_output_read1: temp_dir.path().join("read1_out.fq"),
         new_output_read1_gz: temp_dir.path().join("read1_out.fq.gz"),
@@ -104,21 +150,39 @@ pub fn setup_integration_test(
             delim_underscore_read2: temp_dir.path().join("delim_underscore_read2.fq"),
             umi_read2_switch_read1: temp_dir.path().join("umi_read2_switch_read1.fq"),
             umi_read2_switch_read2: temp_dir.path().join("umi_read2_switch_read2.fq"),
+            internal_read1: temp_dir.path().join("internal_read1.fq"),
+            internal_read2: temp_dir.path().join("internal_read2.fq"),
+            umi_structure_read1: temp_dir.path().join("umi_structure_read1.fq"),
+            umi_structure_read2: temp_dir.path().join("umi_structure_read2.fq"),
+            correct_index1: temp_dir.path().join("correct_index1.fq"),
+            correct_index2: temp_dir.path().join("correct_index2.fq"),
+            dual_umi_read1: temp_dir.path().join("dual_umi_read1.fq"),
+            dual_umi_read2: temp_dir.path().join("dual_umi_read2.fq"),
+            duplex_read1: temp_dir.path().join("duplex_read1.fq"),
+            duplex_read2: temp_dir.path().join("duplex_read2.fq"),
+            duplex_tags_read1: temp_dir.path().join("duplex_tags_read1.fq"),
+            duplex_tags_read2: temp_dir.path().join("duplex_tags_read2.fq"),
+            revcomp_read1: temp_dir.path().join("revcomp_read1.fq"),
+            revcomp_read2: temp_dir.path().join("revcomp_read2.fq"),
+            tags_read1: temp_dir.path().join("tags_read1.fq"),
+            tags_read2: temp_dir.path().join("tags_read2.fq"),
+            comment_read1: temp_dir.path().join("comment_read1.fq"),
+            correct_interleaved: temp_dir.path().join("correct_interleaved.fq"),
         };
         Some(temp)
     } else {
         None
     };
 
-    return (cmd, temp_dir, test_files, test_output);
+    (cmd, temp_dir, test_files, test_output)
 }
 
 // Function to compare two files, used to test if the program output matches the reference.
 #[allow(dead_code)]
 pub fn verify_file_contents(test_file: &PathBuf, reference_file: &PathBuf) -> Result<bool> {
-    let test_file_content = std::fs::read_to_string(&test_file)
+    let test_file_content = std::fs::read_to_string(test_file)
         .map_err(|err| anyhow!("Failed to read test file: {}", err))?;
-    let reference_file_content = std::fs::read_to_string(&reference_file)
+    let reference_file_content = std::fs::read_to_string(reference_file)
         .map_err(|err| anyhow!("Failed to read reference file: {}", err))?;
 
     let predicate_fn = predicate::str::diff(reference_file_content);
@@ -158,3 +222,45 @@ pub fn verify_file_binary(test_file: &PathBuf, reference_file: &PathBuf) -> Resu
         ))
     }
 }
+
+// Unmapped BAM record with the fields relevant for the tests.
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct BamRecord {
+    pub name: String,
+    pub flag: u16,
+    pub seq_len: usize,
+    pub tags: Vec<u8>,
+}
+
+// Decompresses a BAM file and returns its header text and records.
+#[allow(dead_code)]
+pub fn read_bam(path: &PathBuf) -> Result<(String, Vec<BamRecord>)> {
+    let mut data = Vec::new();
+    flate2::read::MultiGzDecoder::new(std::fs::File::open(path)?).read_to_end(&mut data)?;
+    if !data.starts_with(b"BAM\x01") {
+        return Err(anyhow!("{} is no BAM file!", path.display()));
+    }
+    let int = |pos: usize| i32::from_le_bytes(data[pos..pos + 4].try_into().unwrap());
+
+    let l_text = int(4) as usize;
+    let header = String::from_utf8(data[8..8 + l_text].to_vec())?;
+    let mut pos = 8 + l_text + 4;
+
+    let mut records = Vec::new();
+    while pos < data.len() {
+        let block_size = int(pos) as usize;
+        let record = &data[pos + 4..pos + 4 + block_size];
+        let l_read_name = record[8] as usize;
+        let seq_len = i32::from_le_bytes(record[16..20].try_into().unwrap()) as usize;
+        let tags_start = 32 + l_read_name + seq_len.div_ceil(2) + seq_len;
+        records.push(BamRecord {
+            name: String::from_utf8(record[32..32 + l_read_name - 1].to_vec())?,
+            flag: u16::from_le_bytes([record[14], record[15]]),
+            seq_len,
+            tags: record[tags_start..].to_vec(),
+        });
+        pos += 4 + block_size;
+    }
+    Ok((header, records))
+}
@@ -0,0 +1,87 @@
+use assert_cmd::Command;
+use assert_fs::prelude::*;
+use auxiliary::verify_file_contents;
+use predicates::prelude::*;
+use std::error::Error;
+
+#[path = "auxiliary.rs"]
+mod auxiliary;
+
+type TestResult = Result<(), Box<dyn Error>>;
+
+#[test]
+fn comment_fails_without_arguments() {
+    let mut cmd = Command::cargo_bin(assert_cmd::crate_name!()).unwrap();
+
+    cmd.arg("comment");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "error: the following required arguments were not provided",
+        ))
+        .stderr(predicate::str::contains("--in <R1_IN>"));
+}
+
+#[test]
+fn comment_produces_correct_output() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("comment")
+        .arg("--in")
+        .arg(test_files.comment_umi)
+        .arg("--index_segment")
+        .arg("2")
+        .arg("--out")
+        .arg(&test_files.new_output_read1);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Processed 3 records"));
+
+    let reference = test_output.unwrap();
+    let results = verify_file_contents(&test_files.new_output_read1, &reference.comment_read1)?;
+    assert!(results);
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn comment_writes_rx_tag_without_qualities() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("comment")
+        .arg("--in")
+        .arg(test_files.comment_umi)
+        .arg("--header_format")
+        .arg("tags")
+        .arg("--out")
+        .arg(&test_files.new_output_read1);
+
+    cmd.assert().success();
+
+    temp_dir
+        .child("read1_out.fq")
+        .assert(predicate::str::contains(
+            "@SCILIFELAB:500:NGISTLM:1:1101:19994:1016 1:N:0:GCTTCAGGGT\tRX:Z:CCTGAGACC\n",
+        ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn comment_fails_on_missing_index_segment() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("comment")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--index_segment")
+        .arg("3");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Read SCILIFELAB:500:NGISTLM:1:1101:19994:1016 carries no index segment 3",
+    ));
+
+    temp_dir.close().unwrap();
+}
@@ -20,7 +20,7 @@ fn external_fails_without_arguments() {
             "error: the following required arguments were not provided",
         ))
         .stderr(predicate::str::contains("--in <R1_IN>"))
-        .stderr(predicate::str::contains("--in2 <R2_IN>"))
+        .stderr(predicate::str::contains("--in2 <R2_IN>").not())
         .stderr(predicate::str::contains("--umi <RU_IN>"));
 }
 
@@ -299,3 +299,629 @@ fn external_fails_on_read_id_mismatch() {
 
     temp_dir.close().unwrap();
 }
+
+#[test]
+fn external_fails_on_invalid_umi_structure() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--umi_structure")
+        .arg("8X+T");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid value '8X+T'"))
+        .stderr(predicate::str::contains("Invalid segment type 'X'"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_if_umi_structure_does_not_fit() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--umi_structure")
+        .arg("8M");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to include the UMIs"))
+        .stderr(predicate::str::contains(
+            "with a length of 9 bases does not fit the read structure 8M",
+        ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_with_stdin_for_multiple_inputs() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg("-")
+        .arg("--in2")
+        .arg("-")
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--out")
+        .arg(test_files.new_output_read1)
+        .arg("--out2")
+        .arg(test_files.new_output_read2);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Only one input may be streamed via `-`",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_with_stdin_and_no_output_path() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg("-")
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Output paths can't be derived for input from stdin",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_on_unsupported_compression() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+
+    // only the magic bytes matter for the detection
+    let bzip2_input = temp_dir.child("read1.fq.bz2");
+    bzip2_input.write_binary(b"BZh91AY&SY").unwrap();
+
+    cmd.arg("external")
+        .arg("--in")
+        .arg(bzip2_input.path())
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi);
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to read records from"))
+        .stderr(predicate::str::contains(
+            "is compressed with Bzip2, which is not supported",
+        ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_on_truncated_umi_file() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi_truncated);
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to include the UMIs"))
+        .stderr(predicate::str::contains(
+            "umi_truncated.fq ended after 6 records, but the other input files contain more records",
+        ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_on_truncated_read_files() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+
+    // The read IDs of the UMI file are identical to those of the read files.
+    cmd.arg("external")
+        .arg("--in")
+        .arg(&test_files.umi_truncated)
+        .arg("--in2")
+        .arg(&test_files.umi_truncated)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--out")
+        .arg(test_files.new_output_read1)
+        .arg("--out2")
+        .arg(test_files.new_output_read2);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "umi_truncated.fq ended after 6 records",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_unordered_umi_fails_on_missing_umi() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi_truncated)
+        .arg("--unordered_umi");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("No UMI record found for read"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_memory_limit_requires_unordered_umi() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi_unordered)
+        .arg("--memory_limit")
+        .arg("10");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--unordered_umi"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_on_mate_suffix_without_id_rules() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi_mate_suffix);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "IDs of UMI and read records mismatch",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_on_invalid_id_rule() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi_mate_suffix)
+        .arg("--id_rules")
+        .arg("mate_suffix,everything");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid value 'everything'"))
+        .stderr(predicate::str::contains("sra_prefix"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_on_out2_without_in2() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--out2")
+        .arg(temp_dir.path().join("read2_out.fq"));
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--in2 <R2_IN>"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_with_more_outputs_than_inputs() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--out")
+        .arg(test_files.new_output_read1)
+        .arg("--out")
+        .arg(test_files.new_output_read2);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "2 output files were specified with --out, but only 1 input files with --in.",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_on_unsorted_second_umi_file() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--umi2")
+        .arg(test_files.umi_unordered);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "IDs of the records in both UMI files mismatch",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_umi_separator_requires_second_umi_file() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--umi_separator")
+        .arg("+");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--umi2 <RU2_IN>"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_duplex_requires_second_umi_file() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--duplex");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--umi2 <RU2_IN>"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_umi_range_conflicts_with_umi_structure() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--umi_structure")
+        .arg("8M+S")
+        .arg("--umi_length")
+        .arg("8");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_header_preset_conflicts_with_delim() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--header_preset")
+        .arg("umi_tools")
+        .arg("--delim")
+        .arg("_");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_short_umi_requires_umi_range() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--short_umi")
+        .arg("pad");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "the following required arguments were not provided",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_rejects_excessive_memory_limit() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--unordered_umi")
+        .arg("--memory_limit")
+        .arg(u64::MAX.to_string());
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid value"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_fails_on_unknown_header_template_placeholder() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--header_template")
+        .arg("{id}_{barcode}");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Unknown placeholder {barcode} in header template",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_correct_numbers_fails_without_read_number() {
+    let (mut cmd, temp_dir, _test_files, _test_output) = auxiliary::setup_integration_test(false);
+    let reads = temp_dir.child("reads_without_comment.fq");
+    reads.write_str("@read_1\nACGT\n+\nFFFF\n").unwrap();
+    let umis = temp_dir.child("umis_without_comment.fq");
+    umis.write_str("@read_1\nTTGG\n+\nFFFF\n").unwrap();
+
+    cmd.arg("external")
+        .arg("--in")
+        .arg(reads.path())
+        .arg("--umi")
+        .arg(umis.path())
+        .arg("--correct_numbers");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Read read_1 carries its read number neither in a CASAVA 1.8 comment",
+        ))
+        .stderr(predicate::str::contains("panicked").not());
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_correct_numbers_fixes_mate_suffixes() {
+    let (mut cmd, temp_dir, _test_files, _test_output) = auxiliary::setup_integration_test(false);
+    let reads = temp_dir.child("reads_with_suffix.fq");
+    reads.write_str("@read_1/3\nACGT\n+\nFFFF\n").unwrap();
+    let umis = temp_dir.child("umis_with_suffix.fq");
+    umis.write_str("@read_1/2\nTTGG\n+\nFFFF\n").unwrap();
+
+    cmd.arg("external")
+        .arg("--in")
+        .arg(reads.path())
+        .arg("--umi")
+        .arg(umis.path())
+        .arg("--id_rules")
+        .arg("mate_suffix")
+        .arg("--correct_numbers")
+        .arg("--out")
+        .arg(temp_dir.path().join("out.fq"));
+
+    cmd.assert().success();
+
+    temp_dir
+        .child("out.fq")
+        .assert("@read_1/1:TTGG\nACGT\n+\nFFFF\n");
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_writes_unaligned_bam() -> Result<(), Box<dyn std::error::Error>> {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.bam_read1)
+        .arg("--in2")
+        .arg(test_files.bam_read2)
+        .arg("--umi")
+        .arg(test_files.bam_umi)
+        .arg("--output_format")
+        .arg("bam")
+        .arg("--read_group")
+        .arg("ID:rg1\\tSM:sample1\\tPL:ILLUMINA");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Output will be saved to"))
+        .stdout(predicate::str::contains("Processed 2 records"));
+
+    let (header, records) = auxiliary::read_bam(&temp_dir.path().join("bam_read1_with_UMIs.bam"))?;
+    assert!(header.contains("@RG\tID:rg1\tSM:sample1\tPL:ILLUMINA\n"));
+    assert_eq!(records.len(), 4);
+    assert_eq!(
+        records.iter().map(|r| r.flag).collect::<Vec<_>>(),
+        [77, 141, 77, 141]
+    );
+    assert_eq!(records[1].name, "frag1");
+    assert_eq!(records[1].seq_len, 9);
+    assert_eq!(records[1].tags, b"RXZCCTGAG\0QXZFFF:FF\0RGZrg1\0");
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_read_group_requires_bam_output() {
+    for format in [None, Some("fastq"), Some("interleaved")] {
+        let (mut cmd, temp_dir, test_files, _test_output) =
+            auxiliary::setup_integration_test(false);
+        cmd.arg("external")
+            .arg("--in")
+            .arg(test_files.read1)
+            .arg("--umi")
+            .arg(test_files.umi)
+            .arg("--read_group")
+            .arg("ID:rg1");
+        if let Some(format) = format {
+            cmd.arg("--output_format").arg(format);
+        }
+
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "A read group can only be assigned to BAM output.",
+        ));
+
+        temp_dir.close().unwrap();
+    }
+}
+
+#[test]
+fn external_bam_requires_matching_qualities() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--output_format")
+        .arg("bam");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "BAM output requires one quality per base.",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn external_reads_unaligned_sam() -> Result<(), Box<dyn std::error::Error>> {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(&test_files.ubam_reads)
+        .arg("--in2")
+        .arg(&test_files.ubam_reads)
+        .arg("--umi")
+        .arg(&test_files.ubam_reads)
+        .arg("--out")
+        .arg(temp_dir.path().join("converted_read1.fq"))
+        .arg("--out2")
+        .arg(temp_dir.path().join("converted_read2.fq"));
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Processed 2 records"));
+
+    // Mates are selected by their flags, the UMI is taken from the RX and QX tags and the index from BC.
+    temp_dir.child("converted_read1.fq").assert(
+        "@frag1:CCTGAG 1:N:0:ACGT\nACGTACGTAC\n+\nFFFFF:FFFF\n\
+         @frag2:TTAGGC 1:Y:0:ACGT\nTTTTGGGG\n+\nFFFFFFFF\n",
+    );
+    // Reverse strand reads are restored to their sequenced orientation.
+    temp_dir.child("converted_read2.fq").assert(
+        "@frag1:CCTGAG 2:N:0:ACGT\nTGGGGCCCC\n+\n,FFFF:FFF\n\
+         @frag2:TTAGGC 2:Y:0:ACGT\nAAAACCCC\n+\nFFFFFFFF\n",
+    );
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_reads_unaligned_bam() -> Result<(), Box<dyn std::error::Error>> {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.bam_read1)
+        .arg("--in2")
+        .arg(test_files.bam_read2)
+        .arg("--umi")
+        .arg(test_files.bam_umi)
+        .arg("--output_format")
+        .arg("bam");
+    cmd.assert().success();
+
+    // The UMIs of the BAM output are embedded into the FastQ headers again.
+    let bam = temp_dir.path().join("bam_read1_with_UMIs.bam");
+    let mut cmd = Command::cargo_bin(assert_cmd::crate_name!())?;
+    cmd.arg("external")
+        .arg("--in")
+        .arg(&bam)
+        .arg("--in2")
+        .arg(&bam)
+        .arg("--umi")
+        .arg(&bam)
+        .arg("--out")
+        .arg(temp_dir.path().join("converted_read1.fq"))
+        .arg("--out2")
+        .arg(temp_dir.path().join("converted_read2.fq"));
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Processed 2 records"));
+
+    temp_dir
+        .child("converted_read1.fq")
+        .assert("@frag1:CCTGAG\nACGTACGTAC\n+\nFFFFF:FFFF\n@frag2:TTAGGC\nTTTTGGGG\n+\nFFFFFFFF\n");
+
+    temp_dir.close()?;
+    Ok(())
+}
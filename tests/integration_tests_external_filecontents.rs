@@ -2,6 +2,7 @@ use assert_fs::prelude::*;
 use auxiliary::{verify_file_binary, verify_file_contents};
 use predicates::prelude::*;
 use std::error::Error;
+use std::io::Read;
 
 #[path = "auxiliary.rs"]
 mod auxiliary;
@@ -173,6 +174,37 @@ fn external_underscore_delimiter() -> TestResult {
     Ok(())
 }
 
+#[test]
+fn external_umi_read_structure() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--umi_structure")
+        .arg("8M+S");
+
+    cmd.assert().success(); //further assertions have been tested in other tests
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.umi_structure_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.umi_structure_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
 // Not really a serious test, but one can also integrate the read sequence into the UMI header if needed ;-)
 
 #[test]
@@ -329,3 +361,979 @@ fn external_produces_correct_compressed_output_thread_limit() -> TestResult {
     temp_dir.close()?;
     Ok(())
 }
+
+#[test]
+fn external_reads_compressed_input_from_stdin() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg("-")
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--out")
+        .arg(test_files.new_output_read1)
+        .arg("--out2")
+        .arg(test_files.new_output_read2)
+        .pipe_stdin(test_files.read1_gz)?;
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_out.fq").to_path_buf(),
+        &reference.correct_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_out.fq").to_path_buf(),
+        &reference.correct_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_writes_output_to_stdout() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--out")
+        .arg("-")
+        .arg("--out2")
+        .arg(test_files.new_output_read2);
+
+    let reference = test_output.unwrap();
+
+    // Only sequence data may be written to stdout, all status messages go to stderr.
+    cmd.assert()
+        .success()
+        .stdout(
+            predicate::path::eq_file(&reference.correct_read1)
+                .utf8()
+                .unwrap(),
+        )
+        .stderr(predicate::str::contains("Transferring UMIs to records"))
+        .stderr(predicate::str::contains("umi-transfer finished after"));
+
+    verify_file_contents(
+        &temp_dir.child("read2_out.fq").to_path_buf(),
+        &reference.correct_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_unordered_umi_produces_correct_output() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi_unordered)
+        .arg("--unordered_umi");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Indexing UMI records"))
+        .stdout(predicate::str::contains("Processed 10 records"));
+
+    let reference = test_output.unwrap();
+
+    // All UMIs fit into memory, so the order of the reads is retained.
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.correct_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.correct_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_unordered_umi_spills_to_disk() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi_unordered)
+        .arg("--unordered_umi")
+        .arg("--memory_limit")
+        .arg("0")
+        .arg("--tmp_dir")
+        .arg(temp_dir.path());
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "UMI records exceed the memory limit",
+        ))
+        .stdout(predicate::str::contains(
+            "Transferred UMIs to 10 records spilled to disk",
+        ))
+        .stdout(predicate::str::contains("Processed 10 records"));
+
+    let reference = test_output.unwrap();
+
+    // Reads are written in the order of the partitions, so only the sorted records can be compared.
+    for (output, reference) in [
+        ("read1_with_UMIs.fq", &reference.correct_read1),
+        ("read2_with_UMIs.fq", &reference.correct_read2),
+    ] {
+        let sorted_records = |content: String| {
+            let lines: Vec<String> = content.lines().map(String::from).collect();
+            let mut records: Vec<String> = lines.chunks(4).map(|r| r.join("\n")).collect();
+            records.sort();
+            records
+        };
+        assert_eq!(
+            sorted_records(std::fs::read_to_string(temp_dir.child(output).path())?),
+            sorted_records(std::fs::read_to_string(reference)?)
+        );
+    }
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_id_rules_match_mate_suffixes() -> TestResult {
+    for unordered in [false, true] {
+        let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+        cmd.arg("external")
+            .arg("--in")
+            .arg(test_files.read1)
+            .arg("--in2")
+            .arg(test_files.read2)
+            .arg("--umi")
+            .arg(test_files.umi_mate_suffix)
+            .arg("--id_rules")
+            .arg("mate_suffix,coordinates");
+        if unordered {
+            cmd.arg("--unordered_umi");
+        }
+
+        cmd.assert().success().stdout(predicate::str::contains(
+            "20 read IDs matched after stripping mate suffixes",
+        ));
+
+        let reference = test_output.unwrap();
+
+        verify_file_contents(
+            &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+            &reference.correct_read1,
+        )?;
+
+        verify_file_contents(
+            &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+            &reference.correct_read2,
+        )?;
+
+        temp_dir.close()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn external_single_end_produces_correct_output() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Output 1 will be saved to"))
+        .stdout(predicate::str::contains("Output 2").not());
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.correct_read1,
+    )?;
+    temp_dir
+        .child("read2_with_UMIs.fq")
+        .assert(predicate::path::missing());
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_transfers_umi_to_index_reads() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--in")
+        .arg(test_files.index1)
+        .arg("--in")
+        .arg(test_files.index2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--out")
+        .arg(test_files.new_output_read1);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Output 4 will be saved to"))
+        .stdout(predicate::str::contains("Processed 10 records"));
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_out.fq").to_path_buf(),
+        &reference.correct_read1,
+    )?;
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.correct_read2,
+    )?;
+    verify_file_contents(
+        &temp_dir.child("index1_with_UMIs.fq").to_path_buf(),
+        &reference.correct_index1,
+    )?;
+    verify_file_contents(
+        &temp_dir.child("index2_with_UMIs.fq").to_path_buf(),
+        &reference.correct_index2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_keeps_read_numbers_of_index_reads() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--in")
+        .arg(test_files.index1)
+        .arg("--in")
+        .arg(test_files.index2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--correct_numbers");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.corrected_read1,
+    )?;
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.corrected_read2,
+    )?;
+    verify_file_contents(
+        &temp_dir.child("index1_with_UMIs.fq").to_path_buf(),
+        &reference.correct_index1,
+    )?;
+    verify_file_contents(
+        &temp_dir.child("index2_with_UMIs.fq").to_path_buf(),
+        &reference.correct_index2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_joins_dual_umis() -> TestResult {
+    for unordered in [false, true] {
+        let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+        cmd.arg("external")
+            .arg("--in")
+            .arg(test_files.read1)
+            .arg("--in2")
+            .arg(test_files.read2)
+            .arg("--umi")
+            .arg(test_files.umi)
+            .arg("--umi2")
+            .arg(test_files.umi2)
+            .arg("--umi_separator")
+            .arg("+");
+        if unordered {
+            cmd.arg("--unordered_umi");
+        }
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Processed 10 records"));
+
+        let reference = test_output.unwrap();
+
+        verify_file_contents(
+            &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+            &reference.dual_umi_read1,
+        )?;
+
+        verify_file_contents(
+            &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+            &reference.dual_umi_read2,
+        )?;
+
+        temp_dir.close()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn external_writes_duplex_umis_in_canonical_order() -> TestResult {
+    for unordered in [false, true] {
+        let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+        cmd.arg("external")
+            .arg("--in")
+            .arg(test_files.read1)
+            .arg("--in2")
+            .arg(test_files.read2)
+            .arg("--umi")
+            .arg(test_files.umi_duplex1)
+            .arg("--umi2")
+            .arg(test_files.umi_duplex2)
+            .arg("--duplex");
+        if unordered {
+            cmd.arg("--unordered_umi");
+        }
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("Processed 10 records"));
+
+        let reference = test_output.unwrap();
+
+        verify_file_contents(
+            &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+            &reference.duplex_read1,
+        )?;
+
+        verify_file_contents(
+            &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+            &reference.duplex_read2,
+        )?;
+
+        temp_dir.close()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn external_writes_duplex_strand_only_with_tags() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi_duplex1)
+        .arg("--umi2")
+        .arg(test_files.umi_duplex2)
+        .arg("--duplex")
+        .arg("--header_format")
+        .arg("tags");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Processed 10 records"));
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.duplex_tags_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.duplex_tags_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_reverse_complements_umis() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--reverse_complement");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.revcomp_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.revcomp_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_prefixes_reverse_complemented_umis() -> TestResult {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--reverse_complement")
+        .arg("--revcomp_prefix");
+
+    cmd.assert().success();
+
+    temp_dir
+        .child("read1_with_UMIs.fq")
+        .assert(predicate::str::starts_with(
+            "@SCILIFELAB:500:NGISTLM:1:1101:19994:1016:rGGTCTCAGG 1:N:0:",
+        ));
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_umi_length_equals_umi_structure() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--umi_start")
+        .arg("1")
+        .arg("--umi_length")
+        .arg("8");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.umi_structure_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.umi_structure_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_short_umi_policies() -> TestResult {
+    // The UMI reads are 9 bases long, so a range of 4 bases from position 7 exceeds them.
+    let run = |policy: Option<&str>| {
+        let (mut cmd, temp_dir, test_files, _test_output) =
+            auxiliary::setup_integration_test(false);
+        cmd.arg("external")
+            .arg("--in")
+            .arg(test_files.read1)
+            .arg("--umi")
+            .arg(test_files.umi)
+            .arg("--umi_start")
+            .arg("7")
+            .arg("--umi_length")
+            .arg("4");
+        if let Some(policy) = policy {
+            cmd.arg("--short_umi").arg(policy);
+        }
+        (cmd.assert(), temp_dir)
+    };
+
+    let (assert, temp_dir) = run(None);
+    assert.failure().stderr(predicate::str::contains(
+        "with a length of 9 bases does not fit the read structure 6S4M+S",
+    ));
+    temp_dir.close()?;
+
+    let (assert, temp_dir) = run(Some("pad"));
+    assert.success();
+    temp_dir
+        .child("read1_with_UMIs.fq")
+        .assert(predicate::str::starts_with(
+            "@SCILIFELAB:500:NGISTLM:1:1101:19994:1016:ACCN 1:N:0:",
+        ));
+    temp_dir.close()?;
+
+    let (assert, temp_dir) = run(Some("skip"));
+    assert
+        .success()
+        .stdout(predicate::str::contains(
+            "Skipped 10 records with UMI reads too short for the UMI range",
+        ))
+        .stdout(predicate::str::contains("Processed 0 records"));
+    temp_dir
+        .child("read1_with_UMIs.fq")
+        .assert(predicate::str::is_empty());
+    temp_dir.close()?;
+
+    // Skipped fragments must still have matching read IDs.
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi_shuffle)
+        .arg("--umi_start")
+        .arg("7")
+        .arg("--umi_length")
+        .arg("4")
+        .arg("--short_umi")
+        .arg("skip");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "IDs of UMI and read records mismatch",
+    ));
+    temp_dir.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn external_writes_umi_as_sam_tags() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--header_format")
+        .arg("tags");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.tags_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.tags_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_header_preset_umi_tools_equals_underscore_delim() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--header_preset")
+        .arg("umi_tools");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.delim_underscore_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.delim_underscore_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_header_preset_fgbio_writes_sam_tags() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--header-preset")
+        .arg("fgbio");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.tags_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.tags_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_header_template_renders_placeholders() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--header_template")
+        .arg("{id}_{umi} {comment}");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.delim_underscore_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.delim_underscore_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_header_template_with_illumina_fields() -> TestResult {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--header_template")
+        .arg("{id} {readnr}:N:0:{index}+{umi}");
+
+    cmd.assert().success();
+
+    temp_dir
+        .child("read2_with_UMIs.fq")
+        .assert(predicate::str::starts_with(
+            "@SCILIFELAB:500:NGISTLM:1:1101:19994:1016 3:N:0:GCTTCAGGGT+AAGGTAGCGT+CCTGAGACC\n",
+        ));
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_writes_interleaved_output() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--output_format")
+        .arg("interleaved");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Output will be saved to"));
+
+    temp_dir
+        .child("read2_with_UMIs.fq")
+        .assert(predicate::path::missing());
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &test_output.unwrap().correct_interleaved,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_reads_interleaved_input() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read_interleaved)
+        .arg("--interleaved_in")
+        .arg("--umi")
+        .arg(test_files.umi);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Processed 10 records"));
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir
+            .child("read_interleaved_R1_with_UMIs.fq")
+            .to_path_buf(),
+        &reference.correct_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir
+            .child("read_interleaved_R2_with_UMIs.fq")
+            .to_path_buf(),
+        &reference.correct_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_interleaved_input_and_output() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read_interleaved)
+        .arg("--interleaved_in")
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--output_format")
+        .arg("interleaved");
+
+    cmd.assert().success();
+
+    verify_file_contents(
+        &temp_dir
+            .child("read_interleaved_with_UMIs.fq")
+            .to_path_buf(),
+        &test_output.unwrap().correct_interleaved,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_interleaved_input_requires_matching_umis() -> TestResult {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read_interleaved)
+        .arg("--interleaved_in")
+        .arg("--umi")
+        .arg(test_files.umi_shuffle);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "IDs of UMI and read records mismatch",
+    ));
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_writes_bgzf_with_gzi_index() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--gzip")
+        .arg("--compression_format")
+        .arg("bgzf")
+        .arg("--gzi");
+
+    cmd.assert().success();
+
+    // BGZF blocks carry the BC extra subfield.
+    let compressed = std::fs::read(temp_dir.child("read1_with_UMIs.fq.gz"))?;
+    assert_eq!(compressed[..4], [0x1f, 0x8b, 0x08, 0x04]);
+    assert_eq!(compressed[12..14], *b"BC");
+
+    let mut decompressed = String::new();
+    flate2::read::MultiGzDecoder::new(&compressed[..]).read_to_string(&mut decompressed)?;
+    assert_eq!(
+        decompressed,
+        std::fs::read_to_string(test_output.unwrap().correct_read1)?
+    );
+
+    // The small test files fit into a single block, hence the only entry points to the EOF block.
+    let index = std::fs::read(temp_dir.child("read1_with_UMIs.fq.gz.gzi"))?;
+    assert_eq!(index.len(), 24);
+    assert_eq!(index[..8], 1u64.to_le_bytes());
+    assert_eq!(index[8..16], (compressed.len() as u64 - 28).to_le_bytes());
+    assert_eq!(index[16..], (decompressed.len() as u64).to_le_bytes());
+
+    temp_dir
+        .child("read2_with_UMIs.fq.gz.gzi")
+        .assert(predicate::path::exists());
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_gzi_requires_bgzf() -> TestResult {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--gzip")
+        .arg("--compression_format")
+        .arg("gzip")
+        .arg("--gzi");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "A .gzi index can only be written alongside BGZF-compressed FastQ output files.",
+    ));
+    temp_dir.close()?;
+
+    // BAM output is BGZF-compressed as well, but not indexed.
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--gzip")
+        .arg("--compression_format")
+        .arg("bgzf")
+        .arg("--gzi")
+        .arg("--output_format")
+        .arg("bam");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "A .gzi index can only be written alongside BGZF-compressed FastQ output files.",
+    ));
+    temp_dir.close()?;
+
+    Ok(())
+}
+
+#[test]
+fn external_writes_zstd_output() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("external")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--gzip")
+        .arg("--compression_format")
+        .arg("zstd")
+        .arg("--compression_level")
+        .arg("12");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("read1_with_UMIs.fq.zst"));
+
+    let reference = test_output.unwrap();
+    let decompressed = zstd::stream::decode_all(std::fs::File::open(
+        temp_dir.child("read1_with_UMIs.fq.zst"),
+    )?)?;
+    assert_eq!(decompressed, std::fs::read(reference.correct_read1)?);
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn external_reads_zstd_input() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    let read1_zst = temp_dir.child("read1.fq.zst");
+    std::fs::write(
+        &read1_zst,
+        zstd::stream::encode_all(std::fs::File::open(&test_files.read1)?, 3)?,
+    )?;
+
+    cmd.arg("external")
+        .arg("--in")
+        .arg(read1_zst.path())
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi")
+        .arg(test_files.umi)
+        .arg("--out")
+        .arg(temp_dir.child("read1_out.fq").path());
+
+    cmd.assert().success();
+
+    verify_file_contents(
+        &temp_dir.child("read1_out.fq").to_path_buf(),
+        &test_output.unwrap().correct_read1,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
@@ -0,0 +1,261 @@
+use assert_cmd::Command;
+use assert_fs::prelude::*;
+use auxiliary::verify_file_contents;
+use predicates::prelude::*;
+use std::error::Error;
+
+#[path = "auxiliary.rs"]
+mod auxiliary;
+
+type TestResult = Result<(), Box<dyn Error>>;
+
+#[test]
+fn internal_fails_without_arguments() {
+    let mut cmd = Command::cargo_bin(assert_cmd::crate_name!()).unwrap();
+
+    cmd.arg("internal");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "error: the following required arguments were not provided",
+        ))
+        .stderr(predicate::str::contains("--in <R1_IN>"))
+        .stderr(predicate::str::contains("--in2 <R2_IN>"));
+}
+
+#[test]
+fn internal_fails_without_umi_length() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2);
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "error: the following required arguments were not provided",
+        ))
+        .stderr(predicate::str::contains("--umi_length"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn internal_with_minimal_arguments_compressed() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1_gz)
+        .arg("--in2")
+        .arg(test_files.read2_gz)
+        .arg("--umi_length")
+        .arg("8")
+        .arg("--gzip");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Extracting UMIs from records"))
+        .stdout(predicate::str::contains("Processed 10 records"))
+        .stdout(predicate::str::contains("umi-transfer finished after"));
+
+    temp_dir
+        .child("read1_with_UMIs.fq.gz")
+        .assert(predicate::path::exists());
+
+    temp_dir
+        .child("read2_with_UMIs.fq.gz")
+        .assert(predicate::path::exists());
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn internal_produces_correct_output() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi_length")
+        .arg("8");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.internal_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.internal_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn internal_read_structure_equals_umi_length() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--read_structure")
+        .arg("8M+T");
+
+    cmd.assert().success();
+
+    let reference = test_output.unwrap();
+
+    verify_file_contents(
+        &temp_dir.child("read1_with_UMIs.fq").to_path_buf(),
+        &reference.internal_read1,
+    )?;
+
+    verify_file_contents(
+        &temp_dir.child("read2_with_UMIs.fq").to_path_buf(),
+        &reference.internal_read2,
+    )?;
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn internal_read_structure_conflicts_with_umi_length() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi_length")
+        .arg("8")
+        .arg("--read_structure")
+        .arg("8M+T");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn internal_fails_on_reads_shorter_than_umi() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi_length2")
+        .arg("500");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to extract the UMIs"))
+        .stderr(predicate::str::contains(
+            "does not fit the read structure 500M+T",
+        ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn internal_fails_on_read_pair_mismatch() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.umi_shuffle)
+        .arg("--umi_length")
+        .arg("8");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "IDs of read 1 and read 2 records mismatch",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn internal_fails_on_truncated_read_file() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.umi_truncated)
+        .arg("--umi_length")
+        .arg("8");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "umi_truncated.fq ended after 6 records",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn internal_writes_umi_as_sam_tags() -> TestResult {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--in2")
+        .arg(test_files.read2)
+        .arg("--umi_length")
+        .arg("8")
+        .arg("--header_format")
+        .arg("tags");
+
+    cmd.assert().success();
+
+    temp_dir
+        .child("read1_with_UMIs.fq")
+        .assert(predicate::str::starts_with(
+            "@SCILIFELAB:500:NGISTLM:1:1101:19994:1016 1:N:0:GCTTCAGGGT+AAGGTAGCGT\tRX:Z:AATCGCCG\tQX:Z:F#FFFFFF\n",
+        ));
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn internal_writes_unaligned_bam() -> TestResult {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("internal")
+        .arg("--in")
+        .arg(test_files.bam_read1)
+        .arg("--in2")
+        .arg(test_files.bam_read2)
+        .arg("--umi_length")
+        .arg("4")
+        .arg("--output_format")
+        .arg("bam")
+        .arg("--out")
+        .arg(temp_dir.path().join("internal.bam"));
+
+    cmd.assert().success();
+
+    let (header, records) = auxiliary::read_bam(&temp_dir.path().join("internal.bam"))?;
+    assert!(header.contains("@RG\tID:A\tSM:bam_read1\n"));
+    assert_eq!(records[0].flag, 77);
+    assert_eq!(records[0].seq_len, 6);
+    assert_eq!(records[0].tags, b"RXZACGT\0QXZFFFF\0RGZA\0");
+    assert_eq!(records[1].flag, 141);
+
+    temp_dir.close()?;
+    Ok(())
+}
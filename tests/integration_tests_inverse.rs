@@ -0,0 +1,102 @@
+use assert_cmd::Command;
+use assert_fs::prelude::*;
+use auxiliary::verify_file_contents;
+use predicates::prelude::*;
+use std::error::Error;
+
+#[path = "auxiliary.rs"]
+mod auxiliary;
+
+type TestResult = Result<(), Box<dyn Error>>;
+
+#[test]
+fn inverse_fails_without_arguments() {
+    let mut cmd = Command::cargo_bin(assert_cmd::crate_name!()).unwrap();
+
+    cmd.arg("inverse");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "error: the following required arguments were not provided",
+        ))
+        .stderr(predicate::str::contains("--in <R1_IN>"));
+}
+
+#[test]
+fn inverse_restores_reads_and_umis() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    let reference = test_output.unwrap();
+    cmd.arg("inverse")
+        .arg("--in")
+        .arg(&reference.correct_read1)
+        .arg("--in2")
+        .arg(&reference.correct_read2)
+        .arg("--out")
+        .arg(&test_files.new_output_read1)
+        .arg("--out2")
+        .arg(&test_files.new_output_read2)
+        .arg("--umi_quality")
+        .arg("F");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Processed 10 records"));
+
+    assert!(verify_file_contents(
+        &test_files.new_output_read1,
+        &test_files.read1
+    )?);
+    assert!(verify_file_contents(
+        &test_files.new_output_read2,
+        &test_files.read2
+    )?);
+
+    temp_dir
+        .child("correct_read1_UMIs.fq")
+        .assert(predicate::str::starts_with(
+            "@SCILIFELAB:500:NGISTLM:1:1101:19994:1016 1:N:0:GCTTCAGGGT+AAGGTAGCGT\nCCTGAGACC\n+\nFFFFFFFFF\n",
+        ));
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn inverse_preserves_qualities_of_tags() {
+    let (mut cmd, temp_dir, _test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("inverse")
+        .arg("--in")
+        .arg(test_output.unwrap().tags_read1)
+        .arg("--header_preset")
+        .arg("fgbio");
+
+    cmd.assert().success();
+
+    temp_dir
+        .child("tags_read1_without_UMIs.fq")
+        .assert(predicate::str::starts_with(
+            "@SCILIFELAB:500:NGISTLM:1:1101:19994:1016 1:N:0:GCTTCAGGGT+AAGGTAGCGT\n",
+        ));
+    temp_dir
+        .child("tags_read1_UMIs.fq")
+        .assert(predicate::str::contains("CCTGAGACC\n+\nFFFFFFFFF\n"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn inverse_fails_on_headers_without_umi() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("inverse")
+        .arg("--in")
+        .arg(test_files.read1)
+        .arg("--delim")
+        .arg("_");
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "No UMI found in the header of read SCILIFELAB:500:NGISTLM:1:1101:19994:1016.",
+    ));
+
+    temp_dir.close().unwrap();
+}
@@ -0,0 +1,143 @@
+use auxiliary::verify_file_contents;
+use predicates::prelude::*;
+use std::error::Error;
+
+#[path = "auxiliary.rs"]
+mod auxiliary;
+
+type TestResult = Result<(), Box<dyn Error>>;
+
+#[test]
+fn rewrite_fails_without_action() {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("rewrite").arg("--in").arg(test_files.read1);
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "error: the following required arguments were not provided",
+        ))
+        .stderr(predicate::str::contains("--strip"));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn rewrite_replaces_delimiter() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    let reference = test_output.unwrap();
+    cmd.arg("rewrite")
+        .arg("--in")
+        .arg(&reference.correct_read1)
+        .arg("--in2")
+        .arg(&reference.correct_read2)
+        .arg("--new_delim")
+        .arg("_")
+        .arg("--out")
+        .arg(&test_files.new_output_read1)
+        .arg("--out2")
+        .arg(&test_files.new_output_read2);
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Processed 10 records"))
+        .stdout(predicate::str::contains("without a detectable UMI").not());
+
+    assert!(verify_file_contents(
+        &test_files.new_output_read1,
+        &reference.delim_underscore_read1
+    )?);
+    assert!(verify_file_contents(
+        &test_files.new_output_read2,
+        &reference.delim_underscore_read2
+    )?);
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn rewrite_names_outputs_after_inputs() -> TestResult {
+    let (mut cmd, temp_dir, _test_files, test_output) = auxiliary::setup_integration_test(true);
+    let reference = test_output.unwrap();
+    let input = temp_dir.path().join("read1_with_UMIs.fq");
+    std::fs::copy(&reference.correct_read1, &input)?;
+    cmd.arg("rewrite")
+        .arg("--in")
+        .arg(&input)
+        .arg("--new_delim")
+        .arg("_");
+
+    cmd.assert().success();
+
+    assert!(verify_file_contents(
+        &temp_dir.path().join("read1_with_UMIs_rewritten.fq"),
+        &reference.delim_underscore_read1
+    )?);
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn rewrite_fails_on_mismatched_pairs() {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("rewrite")
+        .arg("--in")
+        .arg(test_output.unwrap().correct_read1)
+        .arg("--in2")
+        .arg(&test_files.read2)
+        .arg("--strip")
+        .arg("--out")
+        .arg(&test_files.new_output_read1)
+        .arg("--out2")
+        .arg(&test_files.new_output_read2);
+
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "IDs of read 1 and read 2 records mismatch.",
+    ));
+
+    temp_dir.close().unwrap();
+}
+
+#[test]
+fn rewrite_strips_umis() -> TestResult {
+    let (mut cmd, temp_dir, test_files, test_output) = auxiliary::setup_integration_test(true);
+    cmd.arg("rewrite")
+        .arg("--in")
+        .arg(test_output.unwrap().correct_read1)
+        .arg("--strip");
+
+    cmd.assert().success();
+
+    assert!(verify_file_contents(
+        &temp_dir.path().join("correct_read1_without_UMIs.fq"),
+        &test_files.read1
+    )?);
+
+    temp_dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn rewrite_keeps_reads_without_umi() -> TestResult {
+    let (mut cmd, temp_dir, test_files, _test_output) = auxiliary::setup_integration_test(false);
+    cmd.arg("rewrite")
+        .arg("--in")
+        .arg(&test_files.read1)
+        .arg("--strip")
+        .arg("--out")
+        .arg(&test_files.new_output_read1);
+
+    cmd.assert().success().stdout(predicate::str::contains(
+        "Left 10 records without a detectable UMI unchanged",
+    ));
+
+    assert!(verify_file_contents(
+        &test_files.new_output_read1,
+        &test_files.read1
+    )?);
+
+    temp_dir.close()?;
+    Ok(())
+}